@@ -1,13 +1,59 @@
+use std::{num::NonZeroUsize, path::PathBuf, time::Duration};
+
 use anyhow::Result;
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 
-use crate::commands;
+use crate::{commands, core};
 
 #[derive(Debug, Parser)]
 #[command(name = "pit", author, version, about)]
 struct Args {
     #[command(subcommand)]
     command: Option<SubCommands>,
+    /// Print colored text, or one JSON event per line with the messages on stderr
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        alias = "message-format"
+    )]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl From<OutputFormat> for core::Format {
+    fn from(format: OutputFormat) -> core::Format {
+        match format {
+            OutputFormat::Text => core::Format::Text,
+            OutputFormat::Json => core::Format::Json,
+        }
+    }
+}
+
+/// Packages to operate on, all of them by default.
+#[derive(Debug, clap::Args)]
+struct SelectionArgs {
+    /// Select packages by name, glob such as `bench-*`, or position in the file, may be repeated
+    #[arg(short, long = "package", value_name = "PACKAGE")]
+    packages: Vec<String>,
+    /// Leave out packages by name, glob or position, may be repeated
+    #[arg(long, value_name = "PACKAGE")]
+    exclude: Vec<String>,
+}
+
+impl From<SelectionArgs> for core::Selection {
+    fn from(args: SelectionArgs) -> core::Selection {
+        core::Selection {
+            packages: args.packages,
+            exclude: args.exclude,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -15,43 +61,123 @@ enum SubCommands {
     /// Check all package in file
     Check {
         file_path: String,
-        /// Check only the specified package
-        #[arg(short, long)]
-        package: Option<String>,
+        #[command(flatten)]
+        selection: SelectionArgs,
         /// Do not print cargo log messages
         #[arg(short, long)]
         quiet: bool,
+        /// Check in parallel without cargo log messages
+        #[arg(long)]
+        parallel: bool,
+        /// Check at most this many packages at once, one per CPU by default
+        #[arg(short, long, requires = "parallel")]
+        jobs: Option<NonZeroUsize>,
+        /// Check all packages as one workspace sharing a target directory
+        #[arg(long, conflicts_with = "parallel")]
+        workspace: bool,
+        /// Continue with the other packages when one fails
+        #[arg(long, overrides_with = "fail_fast")]
+        keep_going: bool,
+        /// Stop at the first package which fails, the default
+        #[arg(long, overrides_with = "keep_going")]
+        fail_fast: bool,
+        /// Pass this flag to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
+        /// Flags passed to cargo
+        #[arg(last = true)]
+        trailing_cargo_args: Vec<String>,
     },
     /// Build all package in file
     Build {
         file_path: String,
-        /// Build only the specified package
-        #[arg(short, long)]
-        package: Option<String>,
+        #[command(flatten)]
+        selection: SelectionArgs,
         /// Do not print cargo log messages
         #[arg(short, long)]
         quiet: bool,
         /// Build in parallel without cargo log messages
         #[arg(long)]
         parallel: bool,
+        /// Build at most this many packages at once, one per CPU by default
+        #[arg(short, long, requires = "parallel")]
+        jobs: Option<NonZeroUsize>,
+        /// Build all packages as one workspace sharing a target directory
+        #[arg(long, conflicts_with = "parallel")]
+        workspace: bool,
+        /// Print which inputs changed when a package is rebuilt
+        #[arg(long)]
+        explain_rebuild: bool,
+        /// Rebuild even if nothing changed since the last build
+        #[arg(long)]
+        force: bool,
+        /// Also write the summary of the packages to this file as JSON
+        #[arg(long, value_name = "PATH")]
+        summary_file: Option<PathBuf>,
+        /// Continue with the other packages when one fails
+        #[arg(long, overrides_with = "fail_fast")]
+        keep_going: bool,
+        /// Stop at the first package which fails, the default
+        #[arg(long, overrides_with = "keep_going")]
+        fail_fast: bool,
+        /// Pass this flag to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
+        /// Flags passed to cargo
+        #[arg(last = true)]
+        trailing_cargo_args: Vec<String>,
     },
     /// Run all package in file
     Run {
         file_path: String,
-        /// Run only the specified package
-        #[arg(short, long)]
-        package: Option<String>,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Run this binary of the package
+        #[arg(long, requires = "packages")]
+        bin: Option<String>,
+        /// Arguments passed to the executed packages
+        #[arg(last = true)]
+        args: Vec<String>,
         /// Do not print cargo log messages
         #[arg(short, long)]
         quiet: bool,
+        /// Build in parallel without cargo log messages, then run the packages one after another
+        #[arg(long)]
+        parallel: bool,
+        /// Build at most this many packages at once, one per CPU by default
+        #[arg(short, long, requires = "parallel")]
+        jobs: Option<NonZeroUsize>,
+        /// Build all packages as one workspace sharing a target directory
+        #[arg(long, conflicts_with = "parallel")]
+        workspace: bool,
+        /// Print which inputs changed when a package is rebuilt
+        #[arg(long)]
+        explain_rebuild: bool,
+        /// Rebuild even if nothing changed since the last build
+        #[arg(long)]
+        force: bool,
+        /// Also write the summary of the packages to this file as JSON
+        #[arg(long, value_name = "PATH")]
+        summary_file: Option<PathBuf>,
+        /// Continue with the other packages when one fails
+        #[arg(long, overrides_with = "fail_fast")]
+        keep_going: bool,
+        /// Stop at the first package which fails, the default
+        #[arg(long, overrides_with = "keep_going")]
+        fail_fast: bool,
+        /// Pass this flag to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
     },
     /// Build all package in file in release mode
     /// and copy the artifacts to the target directory.
     Release {
         file_path: String,
-        /// Build only the specified package
-        #[arg(short, long)]
-        package: Option<String>,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Copy only this binary of the package
+        #[arg(long, requires = "packages")]
+        bin: Option<String>,
         /// Copy final artifacts to this directory
         #[arg(short, long, default_value = "./")]
         out_dir: String,
@@ -61,6 +187,33 @@ enum SubCommands {
         /// Build in parallel without cargo log messages
         #[arg(long)]
         parallel: bool,
+        /// Build at most this many packages at once, one per CPU by default
+        #[arg(short, long, requires = "parallel")]
+        jobs: Option<NonZeroUsize>,
+        /// Build all packages as one workspace sharing a target directory
+        #[arg(long, conflicts_with = "parallel")]
+        workspace: bool,
+        /// Print which inputs changed when a package is rebuilt
+        #[arg(long)]
+        explain_rebuild: bool,
+        /// Rebuild even if nothing changed since the last build
+        #[arg(long)]
+        force: bool,
+        /// Also write the summary of the packages to this file as JSON
+        #[arg(long, value_name = "PATH")]
+        summary_file: Option<PathBuf>,
+        /// Continue with the other packages when one fails
+        #[arg(long, overrides_with = "fail_fast")]
+        keep_going: bool,
+        /// Stop at the first package which fails, the default
+        #[arg(long, overrides_with = "keep_going")]
+        fail_fast: bool,
+        /// Pass this flag to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
+        /// Flags passed to cargo
+        #[arg(last = true)]
+        trailing_cargo_args: Vec<String>,
     },
     /// Create a new file
     Init {
@@ -84,67 +237,246 @@ enum SubCommands {
         #[arg(short, long, default_value = "./")]
         out_dir: String,
     },
-    /// Remove everything in the cache directory
-    Clean,
+    /// Show the inputs of the identity hash deciding whether a package is rebuilt
+    Identity {
+        file_path: String,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Flag the build passes to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
+        /// Flags the build passes to cargo
+        #[arg(last = true)]
+        trailing_cargo_args: Vec<String>,
+    },
+    /// Explain why packages would be rebuilt since their last successful build
+    Explain {
+        file_path: String,
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Explain the builds of the packages as members of one workspace
+        #[arg(long)]
+        workspace: bool,
+        /// Explain the release builds
+        #[arg(long)]
+        release: bool,
+        /// Flag the build passes to cargo, may be repeated
+        #[arg(long = "cargo-arg", value_name = "ARG", allow_hyphen_values = true)]
+        cargo_args: Vec<String>,
+        /// Flags the build passes to cargo
+        #[arg(last = true)]
+        trailing_cargo_args: Vec<String>,
+    },
+    /// Remove the cache of the given file, or everything in the cache directory
+    Clean {
+        file_path: Option<String>,
+        /// Remove only the cache of the specified package, may be repeated
+        #[arg(short, long = "package", requires = "file_path")]
+        packages: Vec<String>,
+        /// Remove only the release artifacts
+        #[arg(long, conflicts_with = "debug_only")]
+        release_only: bool,
+        /// Remove only the debug artifacts
+        #[arg(long)]
+        debug_only: bool,
+        /// Only show what would be removed and how much space it frees
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Inspect and maintain the cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
     #[doc(hidden)]
     #[clap(hide = true)]
     ListCaches { file_path: String },
 }
 
-pub(crate) fn main() -> Result<()> {
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+    /// List the cache entries of each snippet with their size, last use and state
+    Ls,
+    /// Show the total size and the number of entries in each state
+    Stats,
+    /// Remove cache entries
+    Prune {
+        /// Remove entries unused for this long, e.g. 12h, 30d or 2w
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Option<Duration>,
+        /// Remove the least recently used entries until the cache fits in this size, e.g. 10G
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+        /// Remove entries of snippets or packages that no longer exist
+        #[arg(long)]
+        orphans: bool,
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Detect entries left inconsistent by interrupted builds or damage
+    Verify {
+        /// Discard the identity hashes of damaged entries, so that they are rebuilt
+        #[arg(long)]
+        fix: bool,
+    },
+}
+
+fn parse_duration(input: &str) -> Result<Duration> {
+    core::parse_duration(input)
+}
+
+fn parse_size(input: &str) -> Result<u64> {
+    core::parse_size(input)
+}
+
+/// Returns the exit code of pit.
+pub(crate) fn main() -> Result<i32> {
     let args = Args::parse();
 
+    core::set_format(args.format.into());
+    core::load_config()?;
+    core::install_signal_handler()?;
+    core::recover_orphaned_temp_dirs();
+
+    // Set by `run` to the exit code of the executed packages.
+    let mut exit_code = 0;
     if let Some(command) = args.command {
         match command {
             SubCommands::Check {
                 file_path,
-                package,
+                selection,
                 quiet,
+                parallel,
+                jobs,
+                workspace,
+                cargo_args,
+                trailing_cargo_args,
+                keep_going,
+                fail_fast: _,
             } => {
-                if let Some(package) = package {
-                    commands::check_specified_package(file_path, &package, quiet)?;
+                let options = &core::BuildOptions {
+                    quiet,
+                    cargo_args: [cargo_args, trailing_cargo_args].concat(),
+                    keep_going,
+                    jobs: jobs.map(NonZeroUsize::get),
+                    ..Default::default()
+                };
+                let selection = &selection.into();
+                if workspace {
+                    commands::check_in_workspace(file_path, selection, options)?;
+                } else if parallel {
+                    commands::check_packages_parallel(file_path, selection, options)?;
                 } else {
-                    commands::check_all(file_path, quiet)?;
+                    commands::check_packages(file_path, selection, options)?;
                 }
             }
             SubCommands::Build {
                 file_path,
-                package,
+                selection,
                 quiet,
                 parallel,
+                jobs,
+                workspace,
+                explain_rebuild,
+                force,
+                summary_file,
+                cargo_args,
+                trailing_cargo_args,
+                keep_going,
+                fail_fast: _,
             } => {
-                if let Some(package) = package {
-                    commands::build_specified_package(file_path, &package, quiet)?;
+                let options = &core::BuildOptions {
+                    quiet,
+                    explain_rebuild,
+                    force,
+                    summary_file,
+                    cargo_args: [cargo_args, trailing_cargo_args].concat(),
+                    keep_going,
+                    jobs: jobs.map(NonZeroUsize::get),
+                    ..Default::default()
+                };
+                let selection = &selection.into();
+                if workspace {
+                    commands::build_in_workspace(file_path, selection, options)?;
                 } else if parallel {
-                    commands::build_all_parallel(file_path)?;
+                    commands::build_packages_parallel(file_path, selection, options)?;
                 } else {
-                    commands::build_all(file_path, quiet)?;
+                    commands::build_packages(file_path, selection, options)?;
                 }
             }
             SubCommands::Run {
                 file_path,
-                package,
+                selection,
+                bin,
+                args,
                 quiet,
+                parallel,
+                jobs,
+                workspace,
+                explain_rebuild,
+                force,
+                summary_file,
+                cargo_args,
+                keep_going,
+                fail_fast: _,
             } => {
-                if let Some(package) = package {
-                    commands::run_specified_package(file_path, &package, quiet)?;
+                let options = &core::BuildOptions {
+                    quiet,
+                    explain_rebuild,
+                    force,
+                    summary_file,
+                    bin,
+                    args,
+                    cargo_args,
+                    keep_going,
+                    jobs: jobs.map(NonZeroUsize::get),
+                    ..Default::default()
+                };
+                let selection = &selection.into();
+                exit_code = if workspace {
+                    commands::run_in_workspace(file_path, selection, options)?
+                } else if parallel {
+                    commands::run_packages_parallel(file_path, selection, options)?
                 } else {
-                    commands::run_all(file_path, quiet)?;
-                }
+                    commands::run_packages(file_path, selection, options)?
+                };
             }
             SubCommands::Release {
                 file_path,
-                package,
+                selection,
+                bin,
                 out_dir,
                 quiet,
                 parallel,
+                jobs,
+                workspace,
+                explain_rebuild,
+                force,
+                summary_file,
+                cargo_args,
+                trailing_cargo_args,
+                keep_going,
+                fail_fast: _,
             } => {
-                if let Some(package) = package {
-                    commands::release_specified_package(file_path, &package, out_dir, quiet)?;
+                let options = &core::BuildOptions {
+                    quiet,
+                    explain_rebuild,
+                    force,
+                    summary_file,
+                    bin,
+                    cargo_args: [cargo_args, trailing_cargo_args].concat(),
+                    keep_going,
+                    jobs: jobs.map(NonZeroUsize::get),
+                    ..Default::default()
+                };
+                let selection = &selection.into();
+                if workspace {
+                    commands::release_in_workspace(file_path, selection, out_dir, options)?;
                 } else if parallel {
-                    commands::release_all_parallel(file_path, out_dir)?;
+                    commands::release_packages_parallel(file_path, selection, out_dir, options)?;
                 } else {
-                    commands::release_all(file_path, out_dir, quiet)?;
+                    commands::release_packages(file_path, selection, out_dir, options)?;
                 }
             }
             SubCommands::Init { file_name, out_dir } => {
@@ -163,9 +495,65 @@ pub(crate) fn main() -> Result<()> {
             } => {
                 commands::extract_package(file_path, &package, out_dir)?;
             }
-            SubCommands::Clean => {
-                commands::clean_cache_dir()?;
+            SubCommands::Identity {
+                file_path,
+                selection,
+                cargo_args,
+                trailing_cargo_args,
+            } => {
+                let options = &core::BuildOptions {
+                    cargo_args: [cargo_args, trailing_cargo_args].concat(),
+                    ..Default::default()
+                };
+                commands::show_identity_hashes(file_path, &selection.into(), options)?;
+            }
+            SubCommands::Explain {
+                file_path,
+                selection,
+                workspace,
+                release,
+                cargo_args,
+                trailing_cargo_args,
+            } => {
+                let options = &core::BuildOptions {
+                    cargo_args: [cargo_args, trailing_cargo_args].concat(),
+                    ..Default::default()
+                };
+                commands::explain_rebuild(
+                    file_path,
+                    &selection.into(),
+                    workspace,
+                    release,
+                    options,
+                )?;
+            }
+            SubCommands::Clean {
+                file_path,
+                packages,
+                release_only,
+                debug_only,
+                dry_run,
+            } => {
+                let profile = if release_only {
+                    Some(core::Profile::Release)
+                } else if debug_only {
+                    Some(core::Profile::Debug)
+                } else {
+                    None
+                };
+                commands::clean_cache_dir(file_path, &packages, profile, dry_run)?;
             }
+            SubCommands::Cache { command } => match command {
+                CacheCommands::Ls => commands::list_cache_entries()?,
+                CacheCommands::Stats => commands::cache_stats()?,
+                CacheCommands::Prune {
+                    older_than,
+                    max_size,
+                    orphans,
+                    dry_run,
+                } => commands::prune_cache_entries(older_than, max_size, orphans, dry_run)?,
+                CacheCommands::Verify { fix } => commands::verify_cache_entries(fix)?,
+            },
             SubCommands::ListCaches { file_path } => {
                 commands::list_cached_packages(file_path)?;
             }
@@ -175,5 +563,5 @@ pub(crate) fn main() -> Result<()> {
         cmd.print_help()?;
     }
 
-    Ok(())
+    Ok(exit_code)
 }
@@ -1,8 +1,11 @@
 mod add;
 mod build;
+mod cache;
 mod check;
 mod clean;
+mod explain;
 mod extract;
+mod identity;
 mod init;
 mod list;
 mod list_caches;
@@ -10,12 +13,17 @@ mod release;
 mod run;
 
 pub(crate) use add::add_package;
-pub(crate) use build::{build_all, build_all_parallel, build_specified_package};
-pub(crate) use check::{check_all, check_specified_package};
+pub(crate) use build::{build_in_workspace, build_packages, build_packages_parallel};
+pub(crate) use cache::{
+    cache_stats, list_cache_entries, prune_cache_entries, verify_cache_entries,
+};
+pub(crate) use check::{check_in_workspace, check_packages, check_packages_parallel};
 pub(crate) use clean::clean_cache_dir;
+pub(crate) use explain::explain_rebuild;
 pub(crate) use extract::extract_package;
+pub(crate) use identity::show_identity_hashes;
 pub(crate) use init::init_snippet;
 pub(crate) use list::list_packages;
 pub(crate) use list_caches::list_cached_packages;
-pub(crate) use release::{release_all, release_all_parallel, release_specified_package};
-pub(crate) use run::{run_all, run_specified_package};
+pub(crate) use release::{release_in_workspace, release_packages, release_packages_parallel};
+pub(crate) use run::{run_in_workspace, run_packages, run_packages_parallel};
@@ -1,49 +1,72 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
-use rayon::prelude::*;
 
-use crate::core::{build, packages_from_path};
+use crate::core::{
+    build, build_workspace, for_each_package, packages_and_skipped_from_path, par_for_each_package,
+    parallel_options, with_summary, BuildOptions, Progress, Selection,
+};
 
-pub(crate) fn build_specified_package<P: AsRef<Path>>(
+pub(crate) fn build_packages<P: AsRef<Path>>(
     file_path: P,
-    package: &str,
-    quiet: bool,
+    selection: &Selection,
+    options: &BuildOptions,
 ) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
-
-    packages_from_path(file_path)
-        .iter()
-        .find(|x| x.name == package)
-        .iter()
-        .for_each(|package| {
-            build(file_name, package, false, quiet).expect("Failed to build.");
-        });
 
-    Ok(())
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    with_summary(&selected.clone(), options, |options| {
+        for_each_package(selected, "build", options, |package| {
+            build(file_path, package, &packages, false, options)?;
+            Ok(())
+        })
+    })?;
+    skipped.check()
 }
 
-pub(crate) fn build_all<P: AsRef<Path>>(file_path: P, quiet: bool) -> Result<()> {
+pub(crate) fn build_packages_parallel<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path).iter().for_each(|package| {
-        build(file_name, package, false, quiet).expect("Failed to build.");
-    });
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select(&packages)?;
+    with_summary(&selected, options, |options| {
+        let progress = Arc::new(Progress::new(selected.iter().map(|x| x.name.as_str())));
+        let options = &BuildOptions {
+            progress: Some(progress.clone()),
+            ..parallel_options(&selected, options)
+        };
+        let result = par_for_each_package(&selected, "build", options, |package| {
+            build(file_path, package, &packages, false, options)?;
+            Ok(())
+        });
 
-    Ok(())
+        progress.print_failed_logs();
+        result
+    })?;
+    skipped.check()
 }
 
-pub(crate) fn build_all_parallel<P: AsRef<Path>>(file_path: P) -> Result<()> {
+pub(crate) fn build_in_workspace<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .par_iter()
-        .for_each(|package| {
-            build(file_name, package, false, true).expect("Failed to build.");
-        });
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    if selected.is_empty() {
+        return skipped.check();
+    }
 
-    Ok(())
+    with_summary(&selected, options, |options| {
+        build_workspace(file_path, &selected, &packages, false, options)?;
+        Ok(())
+    })?;
+    skipped.check()
 }
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::core::{list_cache, prune_cache, show_cache_stats, verify_cache};
+
+pub(crate) fn list_cache_entries() -> Result<()> {
+    list_cache()?;
+
+    Ok(())
+}
+
+pub(crate) fn cache_stats() -> Result<()> {
+    show_cache_stats()?;
+
+    Ok(())
+}
+
+pub(crate) fn prune_cache_entries(
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+    orphans: bool,
+    dry_run: bool,
+) -> Result<()> {
+    prune_cache(older_than, max_size, orphans, dry_run)?;
+
+    Ok(())
+}
+
+pub(crate) fn verify_cache_entries(fix: bool) -> Result<()> {
+    verify_cache(fix)?;
+
+    Ok(())
+}
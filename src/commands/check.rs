@@ -1,33 +1,63 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
 
-use crate::core::{check, packages_from_path};
+use crate::core::{
+    check, check_workspace, for_each_package, packages_and_skipped_from_path, par_for_each_package,
+    parallel_options, BuildOptions, Progress, Selection,
+};
 
-pub(crate) fn check_specified_package<P: AsRef<Path>>(
+pub(crate) fn check_packages<P: AsRef<Path>>(
     file_path: P,
-    package: &str,
-    quiet: bool,
+    selection: &Selection,
+    options: &BuildOptions,
 ) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .iter()
-        .find(|x| x.name == package)
-        .iter()
-        .for_each(|package| check(file_name, package, quiet).expect("Failed to check."));
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    for_each_package(selected, "check", options, |package| {
+        check(file_path, package, &packages, options)
+    })?;
+    skipped.check()
+}
 
-    Ok(())
+pub(crate) fn check_packages_parallel<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<()> {
+    let file_path = file_path.as_ref();
+
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select(&packages)?;
+    let progress = Arc::new(Progress::new(selected.iter().map(|x| x.name.as_str())));
+    let options = &BuildOptions {
+        progress: Some(progress.clone()),
+        ..parallel_options(&selected, options)
+    };
+    let result = par_for_each_package(&selected, "check", options, |package| {
+        check(file_path, package, &packages, options)
+    });
+
+    progress.print_failed_logs();
+    result?;
+    skipped.check()
 }
 
-pub(crate) fn check_all<P: AsRef<Path>>(file_path: P, quiet: bool) -> Result<()> {
+pub(crate) fn check_in_workspace<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .iter()
-        .for_each(|package| check(file_name, package, quiet).expect("Failed to check."));
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    if selected.is_empty() {
+        return skipped.check();
+    }
 
-    Ok(())
+    check_workspace(file_path, &selected, &packages, options)?;
+    skipped.check()
 }
@@ -1,9 +1,21 @@
+use std::path::Path;
+
 use anyhow::Result;
 
-use crate::core::clean;
+use crate::core::{clean, Profile};
 
-pub(crate) fn clean_cache_dir() -> Result<()> {
-    clean()?;
+pub(crate) fn clean_cache_dir<P: AsRef<Path>>(
+    file_path: Option<P>,
+    packages: &[String],
+    profile: Option<Profile>,
+    dry_run: bool,
+) -> Result<()> {
+    clean(
+        file_path.as_ref().map(|x| x.as_ref()),
+        packages,
+        profile,
+        dry_run,
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,27 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::core::{explain, packages_from_path, BuildOptions, Profile, Selection};
+
+pub(crate) fn explain_rebuild<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    workspace: bool,
+    release: bool,
+    options: &BuildOptions,
+) -> Result<()> {
+    let file_path = file_path.as_ref();
+    let profile = if release {
+        Profile::Release
+    } else {
+        Profile::Debug
+    };
+
+    let packages = packages_from_path(file_path)?;
+    for package in selection.select(&packages)? {
+        explain(file_path, package, &packages, workspace, profile, options)?;
+    }
+
+    Ok(())
+}
@@ -2,18 +2,21 @@ use std::path::Path;
 
 use anyhow::Result;
 
-use crate::core::{extract, packages_from_path};
+use crate::core::{extract, packages_from_path, Selection};
 
 pub(crate) fn extract_package<P: AsRef<Path>, Q: AsRef<Path>>(
     file_path: P,
     package: &str,
     out_dir: Q,
 ) -> Result<()> {
-    packages_from_path(file_path)
-        .iter()
-        .find(|x| x.name == package)
-        .iter()
-        .for_each(|package| extract(package, &out_dir).expect("Failed to extract."));
+    let packages = packages_from_path(file_path)?;
+    let selection = Selection {
+        packages: vec![package.to_owned()],
+        ..Default::default()
+    };
+    for package in selection.select(&packages)? {
+        extract(package, &packages, &out_dir)?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::core::{packages_from_path, show_identity, BuildOptions, Selection};
+
+pub(crate) fn show_identity_hashes<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<()> {
+    let file_path = file_path.as_ref();
+
+    let packages = packages_from_path(file_path)?;
+    for package in selection.select(&packages)? {
+        show_identity(file_path, package, &packages, options)?;
+    }
+
+    Ok(())
+}
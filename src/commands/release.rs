@@ -1,57 +1,72 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
-use rayon::prelude::*;
 
-use crate::core::{packages_from_path, release};
+use crate::core::{
+    for_each_package, packages_and_skipped_from_path, par_for_each_package, parallel_options,
+    release, release_workspace, with_summary, BuildOptions, Progress, Selection,
+};
 
-pub(crate) fn release_specified_package<P: AsRef<Path>, Q: AsRef<Path>>(
+pub(crate) fn release_packages<P: AsRef<Path>, Q: AsRef<Path>>(
     file_path: P,
-    package: &str,
+    selection: &Selection,
     out_dir: Q,
-    quiet: bool,
+    options: &BuildOptions,
 ) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
-
-    packages_from_path(file_path)
-        .iter()
-        .find(|x| x.name == package)
-        .iter()
-        .for_each(|package| {
-            release(file_name, package, &out_dir, quiet).expect("Failed to release.")
-        });
 
-    Ok(())
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    with_summary(&selected.clone(), options, |options| {
+        for_each_package(selected, "release", options, |package| {
+            release(file_path, package, &packages, &out_dir, options)
+        })
+    })?;
+    skipped.check()
 }
 
-pub(crate) fn release_all<P: AsRef<Path>, Q: AsRef<Path>>(
+pub(crate) fn release_packages_parallel<P: AsRef<Path>, Q: AsRef<Path> + std::marker::Sync>(
     file_path: P,
+    selection: &Selection,
     out_dir: Q,
-    quiet: bool,
+    options: &BuildOptions,
 ) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path).iter().for_each(|package| {
-        release(file_name, package, &out_dir, quiet).expect("Failed to release.")
-    });
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select(&packages)?;
+    with_summary(&selected, options, |options| {
+        let progress = Arc::new(Progress::new(selected.iter().map(|x| x.name.as_str())));
+        let options = &BuildOptions {
+            progress: Some(progress.clone()),
+            ..parallel_options(&selected, options)
+        };
+        let result = par_for_each_package(&selected, "release", options, |package| {
+            release(file_path, package, &packages, &out_dir, options)
+        });
 
-    Ok(())
+        progress.print_failed_logs();
+        result
+    })?;
+    skipped.check()
 }
 
-pub(crate) fn release_all_parallel<P: AsRef<Path>, Q: AsRef<Path> + std::marker::Sync>(
+pub(crate) fn release_in_workspace<P: AsRef<Path>, Q: AsRef<Path>>(
     file_path: P,
+    selection: &Selection,
     out_dir: Q,
+    options: &BuildOptions,
 ) -> Result<()> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .par_iter()
-        .for_each(|package| {
-            release(file_name, package, &out_dir, true).expect("Failed to release.")
-        });
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    if selected.is_empty() {
+        return skipped.check();
+    }
 
-    Ok(())
+    with_summary(&selected, options, |options| {
+        release_workspace(file_path, &selected, &packages, out_dir, options)
+    })?;
+    skipped.check()
 }
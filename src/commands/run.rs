@@ -1,33 +1,124 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 
-use crate::core::{packages_from_path, run};
+use crate::core::{
+    build, exit_code_of_runs, for_each_package, packages_and_skipped_from_path,
+    par_for_each_package, parallel_options, record_run, run, run_built, run_workspace,
+    with_summary, BuildOptions, Progress, Selection, SkippedSections,
+};
 
-pub(crate) fn run_specified_package<P: AsRef<Path>>(
+/// Returns 0 if every package succeeded, otherwise the exit code of the first package which failed.
+/// Sections of the snippet with errors fail the command if the packages succeeded.
+pub(crate) fn run_packages<P: AsRef<Path>>(
     file_path: P,
-    package: &str,
-    quiet: bool,
-) -> Result<()> {
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<i32> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .iter()
-        .find(|x| x.name == package)
-        .iter()
-        .for_each(|package| run(file_name, package, quiet).expect("Failed to run"));
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select(&packages)?;
+    let exit_code = with_summary(&selected.clone(), options, |options| {
+        let mut statuses = Vec::new();
+        let result = for_each_package(selected, "run", options, |package| {
+            match run(file_path, package, &packages, options)? {
+                Some(status) => record_run(&mut statuses, status, package, options),
+                None => Ok(()),
+            }
+        });
+        exit_code_of_runs(result, &statuses)
+    })?;
+    skipped_check(skipped, exit_code)
+}
+
+/// Builds the packages in parallel, then runs them one after another in the order of the snippet.
+pub(crate) fn run_packages_parallel<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<i32> {
+    let file_path = file_path.as_ref();
+
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select(&packages)?;
+    let exit_code = with_summary(&selected.clone(), options, |options| {
+        // Libraries are not run, so they are not built either.
+        let binaries = selected
+            .iter()
+            .filter(|x| !x.lib)
+            .copied()
+            .collect::<Vec<_>>();
+        let progress = Arc::new(Progress::new(binaries.iter().map(|x| x.name.as_str())));
+        let build_options = &BuildOptions {
+            progress: Some(progress.clone()),
+            ..parallel_options(&binaries, options)
+        };
+        let caches = Mutex::new(Vec::new());
+        let built = par_for_each_package(&binaries, "build", build_options, |package| {
+            // Keeps the entry locked until the package is executed.
+            let built = build(file_path, package, &packages, false, build_options)?;
+            caches.lock().unwrap().push((package.name.clone(), built));
+            Ok(())
+        });
+        progress.print_failed_logs();
+        let built = match built {
+            Err(error) if !options.keep_going => return Err(error),
+            built => built,
+        };
 
-    Ok(())
+        let mut caches = caches.into_inner().unwrap();
+        let runnable = selected
+            .into_iter()
+            .filter(|x| x.lib || caches.iter().any(|(name, _)| *name == x.name))
+            .collect::<Vec<_>>();
+        let mut statuses = Vec::new();
+        let result = for_each_package(runnable, "run", options, |package| {
+            match caches.iter().position(|(name, _)| *name == package.name) {
+                Some(i) => {
+                    let (_, built) = caches.swap_remove(i);
+                    let status = run_built(file_path, package, built, options)?;
+                    record_run(&mut statuses, status, package, options)
+                }
+                // Only prints that the library is skipped.
+                None => {
+                    run(file_path, package, &packages, options)?;
+                    Ok(())
+                }
+            }
+        });
+
+        exit_code_of_runs(built.and(result), &statuses)
+    })?;
+    skipped_check(skipped, exit_code)
 }
 
-pub(crate) fn run_all<P: AsRef<Path>>(file_path: P, quiet: bool) -> Result<()> {
+pub(crate) fn run_in_workspace<P: AsRef<Path>>(
+    file_path: P,
+    selection: &Selection,
+    options: &BuildOptions,
+) -> Result<i32> {
     let file_path = file_path.as_ref();
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap();
 
-    packages_from_path(file_path)
-        .iter()
-        .for_each(|package| run(file_name, package, quiet).expect("Failed to run"));
+    let (packages, skipped) = packages_and_skipped_from_path(file_path)?;
+    let selected = selection.select_in_build_order(&packages)?;
+    if selected.is_empty() {
+        return skipped_check(skipped, 0);
+    }
+
+    let exit_code = with_summary(&selected, options, |options| {
+        run_workspace(file_path, &selected, &packages, options)
+    })?;
+    skipped_check(skipped, exit_code)
+}
 
-    Ok(())
+/// Keeps the exit code of a failed package over the error of the skipped sections.
+fn skipped_check(skipped: SkippedSections, exit_code: i32) -> Result<i32> {
+    if exit_code == 0 {
+        skipped.check()?;
+    }
+    Ok(exit_code)
 }
@@ -1,136 +1,895 @@
-use std::{fs, path::Path, process};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Instant,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use tiny_ansi::TinyAnsi;
 
+mod batch;
+mod build_dir;
 mod cache;
 mod cargo;
+mod config;
+mod identity;
+mod inventory;
+mod journal;
+mod lock;
+mod metadata;
+mod output;
 mod package;
-mod temp_dir;
+mod progress;
+mod selection;
+mod summary;
 mod utils;
 
-pub(crate) use package::packages_from_path;
+pub(crate) use batch::{for_each_package, par_for_each_package, parallel_options};
+pub(crate) use config::load_config;
+pub(crate) use inventory::{list_cache, prune_cache, show_cache_stats, verify_cache};
+pub(crate) use journal::{install_signal_handler, is_interrupted, recover_orphaned_temp_dirs};
+pub(crate) use output::{emit, set_format, Event, Format};
+pub(crate) use package::{packages_and_skipped_from_path, packages_from_path, SkippedSections};
+pub(crate) use progress::Progress;
+pub(crate) use selection::Selection;
+pub(crate) use summary::with_summary;
+pub(crate) use utils::{parse_duration, parse_size};
 
+use build_dir::BuildDir;
 use cache::Cache;
+use identity::Identity;
+use journal::{bail_if_interrupted, discard_identity_hash, Transaction};
+use lock::Lock;
+use metadata::{read_env_file, Metadata};
+use output::{print_line, IdentityComponent};
 use package::Package;
-use temp_dir::TempDir;
-use utils::{create_gitignore, create_src, create_toml, random_name};
+use summary::Summary;
+use utils::{create_gitignore, create_package, dir_size, format_size, random_name};
 
-// Check
+/// Options of the commands building packages.
+#[derive(Clone, Default)]
+pub(crate) struct BuildOptions {
+    /// Do not print cargo log messages.
+    pub(crate) quiet: bool,
+    /// Print which inputs changed when a package is rebuilt.
+    pub(crate) explain_rebuild: bool,
+    /// Build even if the package is cached.
+    pub(crate) force: bool,
+    /// The binary to run or distribute, when a package has several.
+    pub(crate) bin: Option<String>,
+    /// Arguments of the executed packages, replacing those of their metadata.
+    pub(crate) args: Vec<String>,
+    /// Flags passed to cargo after those of the metadata of the packages.
+    pub(crate) cargo_args: Vec<String>,
+    /// Continue with the other packages when one fails.
+    pub(crate) keep_going: bool,
+    /// Packages built at once by the parallel commands, one per CPU if none.
+    pub(crate) jobs: Option<usize>,
+    /// Value of cargo's `--jobs`, set by the parallel commands to share the CPUs between the packages.
+    pub(crate) cargo_jobs: Option<usize>,
+    /// Shows the builds in the progress display of a parallel command, instead of cargo's output.
+    pub(crate) progress: Option<Arc<Progress>>,
+    /// Records what happened to each package, for the table printed at the end of the command.
+    pub(crate) summary: Option<Arc<Summary>>,
+    /// Also writes the summary to this file as JSON.
+    pub(crate) summary_file: Option<PathBuf>,
+}
 
-pub(crate) fn check(file_name: &str, package: &Package, quiet: bool) -> Result<()> {
-    let temp_dir = TempDir::new(&package);
-    let cache = Cache::new(file_name, &package.name);
+/// Flags passed to cargo: those in `[package.metadata.pit]` of the packages, then those of the command line.
+/// Packages sharing the same flags in a workspace pass them once.
+/// `--message-format` is rejected, as pit sets it to read the executables built.
+fn cargo_args(packages: &[&Package], options: &BuildOptions) -> Result<Vec<String>> {
+    let mut package_args: Vec<Vec<String>> = Vec::new();
+    for package in packages {
+        let args = Metadata::of(package)?.cargo_args;
+        if !package_args.contains(&args) {
+            package_args.push(args);
+        }
+    }
 
-    cache.restore(&temp_dir.target_dir)?;
-    cargo::check(&temp_dir.package_dir, quiet)?;
-    cache.store(&temp_dir.target_dir)?;
+    let mut cargo_args = package_args.concat();
+    cargo_args.extend(options.cargo_args.iter().cloned());
+    if let Some(arg) = cargo_args
+        .iter()
+        .find(|x| x.starts_with("--message-format"))
+    {
+        bail!("The cargo flag {arg} is not supported, since pit reads the JSON messages of cargo.");
+    }
+    Ok(cargo_args)
+}
 
-    let _ = temp_dir.remove();
+/// Flags in the identity of a package: its own `cargo-args` and the command-line flags,
+/// so that it does not depend on the packages built with it in a workspace.
+fn identity_cargo_args(package: &Package, options: &BuildOptions) -> Result<Vec<String>> {
+    cargo_args(&[package], options)
+}
 
-    Ok(())
+/// Appends the `--jobs` of a parallel command, unless the flags already set it.
+/// Not part of the identity, since the artifacts do not depend on it.
+fn cargo_jobs(mut cargo_args: Vec<String>, options: &BuildOptions) -> Vec<String> {
+    let is_set = cargo_args
+        .iter()
+        .any(|x| x.starts_with("-j") || x == "--jobs" || x.starts_with("--jobs="));
+    if let (Some(jobs), false) = (options.cargo_jobs, is_set) {
+        cargo_args.extend(["--jobs".to_owned(), jobs.to_string()]);
+    }
+    cargo_args
+}
+
+/// Starts the package in the progress display of a parallel command, if any.
+fn tracker<'a>(
+    package: &'a Package,
+    cache: &Cache,
+    options: &'a BuildOptions,
+) -> Option<cargo::Tracker<'a>> {
+    let progress = options.progress.as_deref()?;
+    let log = cache.dir.join("build.log");
+    progress.start(
+        &package.name,
+        lockfile_packages(&cache.cargo_lock),
+        log.clone(),
+    );
+    Some(cargo::Tracker {
+        progress,
+        package: &package.name,
+        log,
+    })
+}
+
+// Check
+
+pub(crate) fn check(
+    file_path: &Path,
+    package: &Package,
+    packages: &[Package],
+    options: &BuildOptions,
+) -> Result<()> {
+    let cache = Cache::new(file_path, &package.name)?;
+    let _lock = cache.lock(options.progress.as_deref())?;
+    let build_dir = BuildDir::new(&cache.project_dir, package, packages)?;
+
+    emit(Event::CheckStarted {
+        package: package.name.clone(),
+    });
+    let result = cargo::check(
+        &build_dir.package_dir,
+        &cache.target_dir,
+        options.quiet,
+        &cargo_jobs(cargo_args(&[package], options)?, options),
+        tracker(package, &cache, options).as_ref(),
+    );
+    if let Some(progress) = &options.progress {
+        progress.finish(&package.name, result.is_ok());
+    }
+    emit(Event::CheckFinished {
+        package: package.name.clone(),
+        success: result.is_ok(),
+    });
+
+    result
 }
 
 // Build
 
+/// A built cache entry, whose artifacts are not replaced by other builds until it is dropped.
+pub(crate) struct Built {
+    pub(crate) cache: Cache,
+    lock: Lock,
+}
+
 pub(crate) fn build(
-    file_name: &str,
+    file_path: &Path,
     package: &Package,
+    packages: &[Package],
     release: bool,
-    quiet: bool,
-) -> Result<Cache> {
-    let cache = Cache::new(file_name, &package.name);
+    options: &BuildOptions,
+) -> Result<Built> {
+    let cache = Cache::new(file_path, &package.name)?;
+    // Returned with the entry, so that the artifacts are executed or distributed before another process replaces them.
+    let lock = cache.lock(options.progress.as_deref())?;
 
-    let new_identity_hash = package.identity_hash();
-    if !release && cache.is_same_identity_hash(&new_identity_hash) {
+    let profile = if release {
+        Profile::Release
+    } else {
+        Profile::Debug
+    };
+    let cargo_args = identity_cargo_args(package, options)?;
+    let identity = Identity::new(package, packages, &cache, &cargo_args);
+    if !options.force && cache.is_same_identity_hash(&identity.hash(), profile) {
+        if let Some(summary) = &options.summary {
+            summary.cached(&package.name, artifact_size(&cache, profile));
+        }
+        emit(Event::BuildFinished {
+            package: package.name.clone(),
+            profile: profile.name(),
+            cached: true,
+            success: true,
+            duration: None,
+            executables: executable_paths(&cache, profile),
+        });
+        if let Some(progress) = &options.progress {
+            progress.cached(&package.name);
+            return Ok(Built { cache, lock });
+        }
         let output_text = format!(
             "Skip building the {} package because it is cached.",
             &package.name
         )
         .bright_green()
         .bold();
-        println!("{output_text}");
+        print_line(output_text);
 
-        return Ok(cache);
+        return Ok(Built { cache, lock });
     }
 
-    let output_text = format!("Build {} package", &package.name)
-        .bright_green()
-        .bold();
-    println!("{output_text}");
+    if options.progress.is_none() {
+        let output_text = format!("Build {} package", &package.name)
+            .bright_green()
+            .bold();
+        print_line(output_text);
+    }
+    emit(Event::BuildStarted {
+        package: package.name.clone(),
+        profile: profile.name(),
+    });
+    if options.explain_rebuild {
+        explain_rebuild(&identity.rebuild_reasons(&cache, profile));
+    }
 
-    let temp_dir = TempDir::new(&package);
+    bail_if_interrupted()?;
+    let _transaction = Transaction::begin(
+        &cache.dir,
+        if release { "release build" } else { "build" },
+        options.progress.as_deref(),
+    )?;
+    // The identity hash is discarded first,
+    // so that it never describes artifacts of an unfinished build.
+    cache.delete_identity_hash(profile)?;
 
-    cache.restore(&temp_dir.target_dir)?;
-    if let Err(e) = cargo::build(&temp_dir.package_dir, release, quiet) {
-        cache.store(&temp_dir.target_dir)?;
-        let _ = cache.delete_identity_hash();
-        return Err(e);
+    let build_dir = BuildDir::new(&cache.project_dir, package, packages)?;
+    let started = Instant::now();
+    let result = cargo::build(
+        &build_dir.package_dir,
+        &cache.target_dir,
+        release,
+        options.quiet,
+        &cargo_jobs(cargo_args.clone(), options),
+        tracker(package, &cache, options).as_ref(),
+    );
+    if let Some(progress) = &options.progress {
+        progress.finish(&package.name, result.is_ok());
+    }
+    let build_time = started.elapsed();
+    if result.is_err() {
+        emit(Event::BuildFinished {
+            package: package.name.clone(),
+            profile: profile.name(),
+            cached: false,
+            success: false,
+            duration: Some(build_time.as_secs_f64()),
+            executables: Vec::new(),
+        });
     }
-    cache.store(&temp_dir.target_dir)?;
+    let executables = result?;
+    bail_if_interrupted()?;
 
-    // Cache is also used for release build,
-    // but compiled each time.
-    if !release {
-        cache.write_identity_hash(&new_identity_hash)?;
+    // Sibling libraries are built too, only the executables of the package are kept.
+    let executables = executables
+        .into_iter()
+        .filter(|x| x.package == package.name)
+        .collect();
+    cache.write_executables(executables, profile)?;
+    // Computed again, as the build resolves the lockfile.
+    cache.write_identity(
+        &Identity::new(package, packages, &cache, &cargo_args),
+        profile,
+    )?;
+    if let Some(summary) = &options.summary {
+        summary.built(&package.name, build_time, artifact_size(&cache, profile));
     }
+    emit(Event::BuildFinished {
+        package: package.name.clone(),
+        profile: profile.name(),
+        cached: false,
+        success: true,
+        duration: Some(build_time.as_secs_f64()),
+        executables: executable_paths(&cache, profile),
+    });
+
+    Ok(Built { cache, lock })
+}
 
-    let _ = temp_dir.remove();
+/// Number of packages in the lockfile, roughly the number of crates cargo compiles.
+fn lockfile_packages(cargo_lock: &Path) -> Option<usize> {
+    let content = fs::read_to_string(cargo_lock).ok()?;
+    Some(content.matches("[[package]]").count())
+}
+
+fn executable_paths(cache: &Cache, profile: Profile) -> Vec<PathBuf> {
+    cache
+        .executables(profile)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x.path)
+        .collect()
+}
 
-    Ok(cache)
+/// Total size of the executables of the last build, none for a package without any.
+fn artifact_size(cache: &Cache, profile: Profile) -> Option<u64> {
+    let executables = cache.executables(profile)?;
+    if executables.is_empty() {
+        return None;
+    }
+    Some(
+        executables
+            .iter()
+            .filter_map(|x| fs::metadata(&x.path).ok())
+            .map(|x| x.len())
+            .sum(),
+    )
+}
+
+/// Prints why a package is rebuilt, no reason meaning it was forced.
+fn explain_rebuild(reasons: &[String]) {
+    if reasons.is_empty() {
+        print_line("    --force was given");
+    }
+    for reason in reasons {
+        print_line(format!("    {reason}"));
+    }
 }
 
 // Run
 
-pub(crate) fn run(file_name: &str, package: &Package, quiet: bool) -> Result<()> {
-    fn execute<P: AsRef<Path>>(exe: P) -> Result<()> {
-        let exit_status = process::Command::new(exe.as_ref()).spawn()?.wait()?;
-        if !exit_status.success() {
-            bail!("Failed to execute.");
+/// How an executed package ended.
+pub(crate) struct RunStatus {
+    exit_status: process::ExitStatus,
+}
+
+impl RunStatus {
+    fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        return std::os::unix::process::ExitStatusExt::signal(&self.exit_status);
+        #[cfg(not(unix))]
+        return None;
+    }
+
+    /// The exit code as a shell reports it, 128 + the signal for a killed process.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match (self.exit_status.code(), self.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+
+    /// How the package ended, e.g. "exited with code 3".
+    pub(super) fn description(&self) -> String {
+        match (self.exit_status.code(), self.signal()) {
+            (Some(0), _) => "succeeded".to_owned(),
+            (Some(code), _) => format!("exited with code {code}"),
+            (None, Some(signal)) => format!("killed by signal {signal}"),
+            (None, None) => "failed".to_owned(),
         }
+    }
+}
+
+/// A run which stopped at a failure, pit exiting with the exit code of the package which failed.
+#[derive(Debug)]
+pub(crate) struct RunFailure {
+    pub(crate) exit_code: i32,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for RunFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for RunFailure {}
+
+/// Records how the package ended, failing it if it did not succeed,
+/// so that the next packages are skipped unless `--keep-going` is given.
+pub(crate) fn record_run(
+    statuses: &mut Vec<RunStatus>,
+    status: RunStatus,
+    package: &Package,
+    options: &BuildOptions,
+) -> Result<()> {
+    let (exit_code, description) = (status.exit_code(), status.description());
+    statuses.push(status);
+    if exit_code != 0 && !options.keep_going {
+        bail!("The {} package {description}.", package.name);
+    }
+
+    Ok(())
+}
 
-        Ok(())
+/// The exit code of pit: 0 if every package succeeded,
+/// otherwise the exit code of the first package which failed.
+/// A failed run keeps that exit code in a `RunFailure`.
+pub(crate) fn exit_code_of_runs(result: Result<()>, statuses: &[RunStatus]) -> Result<i32> {
+    let exit_code = statuses
+        .iter()
+        .map(RunStatus::exit_code)
+        .find(|x| *x != 0)
+        .unwrap_or(0);
+    match result {
+        Err(error) if exit_code != 0 => Err(RunFailure { exit_code, error }.into()),
+        result => result.map(|()| exit_code),
     }
+}
+
+/// Builds and executes the package, none being executed for a library.
+pub(crate) fn run(
+    file_path: &Path,
+    package: &Package,
+    packages: &[Package],
+    options: &BuildOptions,
+) -> Result<Option<RunStatus>> {
+    if package.lib {
+        let output_text = format!(
+            "Skip running the {} package because it is a library.",
+            &package.name
+        )
+        .bright_green()
+        .bold();
+        print_line(output_text);
+
+        return Ok(None);
+    }
+    let metadata = Metadata::of(package)?;
+
+    let output_text = format!("Run {} package", &package.name)
+        .bright_green()
+        .bold();
+    print_line(output_text);
+
+    let built = build(file_path, package, packages, false, options)?;
+    execute_built(file_path, package, built, &metadata, options).map(Some)
+}
+
+/// Executes a package already built by `build`, as the parallel `run` does.
+pub(crate) fn run_built(
+    file_path: &Path,
+    package: &Package,
+    built: Built,
+    options: &BuildOptions,
+) -> Result<RunStatus> {
+    let metadata = Metadata::of(package)?;
 
     let output_text = format!("Run {} package", &package.name)
         .bright_green()
         .bold();
-    println!("{output_text}");
+    print_line(output_text);
 
-    let cache = build(file_name, package, false, quiet)?;
-    execute(cache.debug_exe)?;
+    execute_built(file_path, package, built, &metadata, options)
+}
 
-    Ok(())
+fn execute_built(
+    file_path: &Path,
+    package: &Package,
+    built: Built,
+    metadata: &Metadata,
+    options: &BuildOptions,
+) -> Result<RunStatus> {
+    let exe = executable(package, &built.cache, options)?;
+    emit(Event::RunStarted {
+        package: package.name.clone(),
+        executable: exe.clone(),
+    });
+    let started = Instant::now();
+    let exit_status = execute(exe, file_path, metadata, built.lock, options)?;
+    let run_time = started.elapsed();
+    bail_if_interrupted()?;
+
+    let status = RunStatus { exit_status };
+    if let Some(summary) = &options.summary {
+        summary.ran(&package.name, &status, run_time);
+    }
+    emit(Event::RunFinished {
+        package: package.name.clone(),
+        exit_code: status.exit_code(),
+        signal: status.signal(),
+        duration: run_time.as_secs_f64(),
+    });
+    Ok(status)
+}
+
+/// The binary of the package to run, chosen as `cargo run` does.
+fn executable(package: &Package, cache: &Cache, options: &BuildOptions) -> Result<PathBuf> {
+    let executables = cache.executables(Profile::Debug).unwrap_or_default();
+
+    if let Some(name) = options.bin.clone().or_else(|| package.default_run()) {
+        return match executables.into_iter().find(|x| x.name == name) {
+            Some(executable) => Ok(executable.path),
+            None => bail!("The {} package has no binary named {name}.", package.name),
+        };
+    }
+    match executables.as_slice() {
+        [] => bail!("The {} package has no binary.", package.name),
+        [executable] => Ok(executable.path.clone()),
+        _ => bail!(
+            "The {} package has several binaries: {}. Choose one with --bin.",
+            package.name,
+            executables
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Executes the binary as set in `[package.metadata.pit.run]`,
+/// whose paths are relative to the directory of the snippet.
+/// The lock of the entry is released once the process started, so that it does not block other builds while it runs.
+fn execute(
+    exe: PathBuf,
+    file_path: &Path,
+    metadata: &Metadata,
+    lock: Lock,
+    options: &BuildOptions,
+) -> Result<process::ExitStatus> {
+    let run = &metadata.run;
+    let snippet_dir = file_path.parent().unwrap_or(Path::new(""));
+
+    let mut command = process::Command::new(exe);
+    if options.args.is_empty() {
+        command.args(&run.args);
+    } else {
+        command.args(&options.args);
+    }
+    if let Some(env_file) = &run.env_file {
+        command.envs(read_env_file(&snippet_dir.join(env_file))?);
+    }
+    command.envs(&run.env);
+    if let Some(stdin) = &run.stdin {
+        let stdin = snippet_dir.join(stdin);
+        let file = fs::File::open(&stdin)
+            .with_context(|| format!("Failed to open the stdin file {}", stdin.display()))?;
+        command.stdin(file);
+    }
+    if let Some(cwd) = &run.cwd {
+        command.current_dir(snippet_dir.join(cwd));
+    }
+    if output::is_json() {
+        // Keeps stdout for the events.
+        command.stdout(io::stderr());
+    }
+
+    let mut child = command.spawn()?;
+    drop(lock);
+    let exit_status = child.wait()?;
+
+    Ok(exit_status)
 }
 
 // Release
 
 pub(crate) fn release<P: AsRef<Path>>(
-    file_name: &str,
+    file_path: &Path,
     package: &Package,
+    packages: &[Package],
     out_dir: P,
-    quiet: bool,
+    options: &BuildOptions,
 ) -> Result<()> {
-    fn distribute<P: AsRef<Path>, Q: AsRef<Path>>(
-        exe: P,
-        out_dir: Q,
-        exe_name: &str,
-    ) -> Result<()> {
-        let from = exe.as_ref();
-
-        let to = {
-            let out_dir = out_dir.as_ref();
-            fs::create_dir_all(out_dir)?;
-            out_dir.join(exe_name)
-        };
+    let built = build(file_path, package, packages, true, options)?;
+    if package.lib {
+        // A library has no artifact to distribute.
+        return Ok(());
+    }
+    distribute(package, &built.cache, out_dir, options)?;
 
-        fs::copy(from, to)?;
+    Ok(())
+}
 
-        Ok(())
+/// Copies the executables of the release build of the package, or only the chosen one.
+fn distribute<P: AsRef<Path>>(
+    package: &Package,
+    cache: &Cache,
+    out_dir: P,
+    options: &BuildOptions,
+) -> Result<()> {
+    let executables = cache
+        .executables(Profile::Release)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|x| options.bin.as_ref().is_none_or(|bin| &x.name == bin))
+        .collect::<Vec<_>>();
+    if let (Some(bin), true) = (&options.bin, executables.is_empty()) {
+        bail!("The {} package has no binary named {bin}.", package.name);
     }
 
-    let cache = build(file_name, package, true, quiet)?;
-    distribute(&cache.release_exe, &out_dir, &cache.exe_name)?;
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    for executable in executables {
+        let path = out_dir.join(executable.path.file_name().unwrap());
+        fs::copy(&executable.path, &path)?;
+        emit(Event::ArtifactCopied {
+            package: package.name.clone(),
+            path,
+        });
+    }
+
+    Ok(())
+}
+
+// Workspace
+
+/// Names of the members to pass to cargo, none meaning the whole workspace.
+fn member_names<'a>(selected: &[&'a Package], packages: &[Package]) -> Vec<&'a str> {
+    if selected.len() == packages.len() {
+        return Vec::new();
+    }
+    selected.iter().map(|x| x.name.as_str()).collect()
+}
+
+/// Lets cargo build every member it can with `--keep-going`.
+/// Not part of the identity, since the artifacts do not depend on it.
+fn keep_going(mut cargo_args: Vec<String>, options: &BuildOptions) -> Vec<String> {
+    if options.keep_going {
+        cargo_args.push("--keep-going".to_owned());
+    }
+    cargo_args
+}
+
+pub(crate) fn check_workspace(
+    file_path: &Path,
+    selected: &[&Package],
+    packages: &[Package],
+    options: &BuildOptions,
+) -> Result<()> {
+    // Only the shared directories of this cache are used.
+    let cache = Cache::in_workspace(file_path, "")?;
+    let _lock = cache.lock(options.progress.as_deref())?;
+    let build_dir = BuildDir::workspace(&cache.project_dir, packages)?;
+
+    for package in selected {
+        emit(Event::CheckStarted {
+            package: package.name.clone(),
+        });
+    }
+    let result = cargo::check_workspace(
+        &build_dir.package_dir,
+        &cache.target_dir,
+        &member_names(selected, packages),
+        options.quiet,
+        &keep_going(cargo_args(selected, options)?, options),
+    );
+    for package in selected {
+        emit(Event::CheckFinished {
+            package: package.name.clone(),
+            success: result.is_ok(),
+        });
+    }
+
+    result
+}
+
+/// Builds the packages as members of one workspace with a shared target directory,
+/// so that their dependencies are compiled once.
+pub(crate) fn build_workspace(
+    file_path: &Path,
+    selected: &[&Package],
+    packages: &[Package],
+    release: bool,
+    options: &BuildOptions,
+) -> Result<Vec<Built>> {
+    let profile = if release {
+        Profile::Release
+    } else {
+        Profile::Debug
+    };
+    // Only the shared directories and the lock of this cache are used.
+    let cache = Cache::in_workspace(file_path, "")?;
+    // Shared by the returned members, and released when the last of them is dropped.
+    let lock = cache.lock(options.progress.as_deref())?;
+    let built = |caches: Vec<Cache>| {
+        caches
+            .into_iter()
+            .map(|cache| Built {
+                cache,
+                lock: lock.clone(),
+            })
+            .collect()
+    };
+
+    let caches = selected
+        .iter()
+        .map(|package| Cache::in_workspace(file_path, &package.name))
+        .collect::<Result<Vec<Cache>>>()?;
+    let identities = selected
+        .iter()
+        .zip(&caches)
+        .map(|(package, cache)| {
+            let cargo_args = identity_cargo_args(package, options)?;
+            Ok(Identity::new(package, packages, cache, &cargo_args))
+        })
+        .collect::<Result<Vec<Identity>>>()?;
+    let is_cached = caches
+        .iter()
+        .zip(&identities)
+        .all(|(cache, identity)| cache.is_same_identity_hash(&identity.hash(), profile));
+    if !options.force && is_cached {
+        for (package, cache) in selected.iter().zip(&caches) {
+            if let Some(summary) = &options.summary {
+                summary.cached(&package.name, artifact_size(cache, profile));
+            }
+            emit(Event::BuildFinished {
+                package: package.name.clone(),
+                profile: profile.name(),
+                cached: true,
+                success: true,
+                duration: None,
+                executables: executable_paths(cache, profile),
+            });
+            let output_text = format!(
+                "Skip building the {} package because it is cached.",
+                &package.name
+            )
+            .bright_green()
+            .bold();
+            print_line(output_text);
+        }
+
+        return Ok(built(caches));
+    }
+
+    let output_text = format!(
+        "Build {} workspace",
+        file_path.file_stem().unwrap().to_string_lossy()
+    )
+    .bright_green()
+    .bold();
+    print_line(output_text);
+    if options.explain_rebuild {
+        let mut is_forced = true;
+        for ((package, cache), identity) in selected.iter().zip(&caches).zip(&identities) {
+            let reasons = identity.rebuild_reasons(cache, profile);
+            if !reasons.is_empty() {
+                is_forced = false;
+                print_line(format!("  {}", package.name));
+                explain_rebuild(&reasons);
+            }
+        }
+        if is_forced {
+            explain_rebuild(&[]);
+        }
+    }
+
+    bail_if_interrupted()?;
+    let _transaction = Transaction::begin(
+        &cache.dir,
+        if release { "release build" } else { "build" },
+        options.progress.as_deref(),
+    )?;
+    for cache in &caches {
+        cache.delete_identity_hash(profile)?;
+    }
+
+    let build_dir = BuildDir::workspace(&cache.project_dir, packages)?;
+    for package in selected {
+        emit(Event::BuildStarted {
+            package: package.name.clone(),
+            profile: profile.name(),
+        });
+    }
+    let started = Instant::now();
+    let result = cargo::build_workspace(
+        &build_dir.package_dir,
+        &cache.target_dir,
+        &member_names(selected, packages),
+        release,
+        options.quiet,
+        &keep_going(cargo_args(selected, options)?, options),
+    );
+    let build_time = started.elapsed();
+    if result.is_err() {
+        for package in selected {
+            if let Some(summary) = &options.summary {
+                summary.failed(&package.name);
+            }
+            emit(Event::BuildFinished {
+                package: package.name.clone(),
+                profile: profile.name(),
+                cached: false,
+                success: false,
+                duration: Some(build_time.as_secs_f64()),
+                executables: Vec::new(),
+            });
+        }
+    }
+    let executables = result?;
+    bail_if_interrupted()?;
+
+    for (package, cache) in selected.iter().zip(&caches) {
+        let member_executables = executables
+            .iter()
+            .filter(|x| x.package == package.name)
+            .cloned()
+            .collect();
+        cache.write_executables(member_executables, profile)?;
+        cache.write_identity(
+            &Identity::new(
+                package,
+                packages,
+                cache,
+                &identity_cargo_args(package, options)?,
+            ),
+            profile,
+        )?;
+        if let Some(summary) = &options.summary {
+            summary.built(&package.name, build_time, artifact_size(cache, profile));
+        }
+        emit(Event::BuildFinished {
+            package: package.name.clone(),
+            profile: profile.name(),
+            cached: false,
+            success: true,
+            duration: Some(build_time.as_secs_f64()),
+            executables: executable_paths(cache, profile),
+        });
+    }
+
+    Ok(built(caches))
+}
+
+pub(crate) fn run_workspace(
+    file_path: &Path,
+    selected: &[&Package],
+    packages: &[Package],
+    options: &BuildOptions,
+) -> Result<i32> {
+    let metadata = selected
+        .iter()
+        .map(|package| Metadata::of(package))
+        .collect::<Result<Vec<Metadata>>>()?;
+
+    let built = build_workspace(file_path, selected, packages, false, options)?;
+    let mut members = selected
+        .iter()
+        .zip(built.into_iter().zip(metadata))
+        .filter(|(package, _)| !package.lib)
+        .map(|(package, member)| (package.name.as_str(), member))
+        .collect::<Vec<_>>();
+    let binaries = selected.iter().copied().filter(|x| !x.lib);
+    let mut statuses = Vec::new();
+    let result = for_each_package(binaries, "run", options, |package| {
+        let i = members
+            .iter()
+            .position(|(name, _)| *name == package.name)
+            .unwrap();
+        let (_, (built, metadata)) = members.swap_remove(i);
+
+        let output_text = format!("Run {} package", &package.name)
+            .bright_green()
+            .bold();
+        print_line(output_text);
+
+        let status = execute_built(file_path, package, built, &metadata, options)?;
+        record_run(&mut statuses, status, package, options)
+    });
+
+    exit_code_of_runs(result, &statuses)
+}
+
+pub(crate) fn release_workspace<P: AsRef<Path>>(
+    file_path: &Path,
+    selected: &[&Package],
+    packages: &[Package],
+    out_dir: P,
+    options: &BuildOptions,
+) -> Result<()> {
+    let built = build_workspace(file_path, selected, packages, true, options)?;
+    for (package, built) in selected.iter().zip(built) {
+        if !package.lib {
+            distribute(package, &built.cache, &out_dir, options)?;
+        }
+    }
 
     Ok(())
 }
@@ -158,14 +917,41 @@ fn main() {{
     );
     let content = content.trim_start();
 
-    fs::write(file_path, content)?;
+    fs::write(&file_path, content)?;
+    emit(Event::Created {
+        path: file_path.as_ref().to_owned(),
+    });
 
     Ok(())
 }
 
 // List
-// Removed because too simple.
-// For exaple, if want an option to show
+
+/// Prints the names of the packages, or their events in the JSON format.
+pub(crate) fn list(packages: &[Package]) {
+    for package in packages {
+        list_package(package);
+    }
+}
+
+fn list_package(package: &Package) {
+    if !output::is_json() {
+        println!("{}", package.name);
+        return;
+    }
+    emit(Event::Package {
+        name: package.name.clone(),
+        lib: package.lib,
+        start_line: package.start_line,
+        end_line: package.end_line,
+        src_line: package.src_line,
+        dependencies: package
+            .local_dependencies
+            .iter()
+            .map(|x| x.name.clone())
+            .collect(),
+    });
+}
 
 // Add
 
@@ -196,43 +982,328 @@ fn main() {{
     let content = content.trim_start();
 
     fs::write(file_path, content)?;
+    emit(Event::Added { package: name });
 
     Ok(())
 }
 
 // Extract
 
-pub(crate) fn extract<P: AsRef<Path>>(package: &Package, out_dir: P) -> Result<()> {
-    let package_dir = out_dir.as_ref().join(&package.name);
-    fs::create_dir_all(&package_dir)?;
-
-    create_toml(&package_dir, &package.toml)?;
-    create_src(&package_dir, &package.src)?;
-    create_gitignore(&package_dir)?;
+pub(crate) fn extract<P: AsRef<Path>>(
+    package: &Package,
+    packages: &[Package],
+    out_dir: P,
+) -> Result<()> {
+    // Sibling packages are extracted next to the package,
+    // as it refers to them as `../<name>`.
+    for package in package.dependencies(packages).into_iter().chain([package]) {
+        let package_dir = out_dir.as_ref().join(&package.name);
+        create_package(&package_dir, package)?;
+        create_gitignore(&package_dir)?;
+        emit(Event::Extracted {
+            package: package.name.clone(),
+            path: package_dir,
+        });
+    }
 
     Ok(())
 }
 
 // Clean
 
-pub(crate) fn clean() -> Result<()> {
-    for entry in cache::root_dir().read_dir()? {
-        let Ok(entry) = entry else { continue };
-        fs::remove_dir_all(entry.path())?;
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Profile {
+    Debug,
+    Release,
+}
+
+impl Profile {
+    fn name(self) -> &'static str {
+        match self {
+            Profile::Debug => "debug",
+            Profile::Release => "release",
+        }
     }
+}
+
+/// Removes the cache entries of the snippet, or of every snippet,
+/// optionally only those of some packages and only the artifacts of one profile.
+/// Entries in use by another process are left in place.
+pub(crate) fn clean(
+    file_path: Option<&Path>,
+    package_names: &[String],
+    profile: Option<Profile>,
+    dry_run: bool,
+) -> Result<()> {
+    let dir_name = match file_path {
+        Some(file_path) => {
+            let file_path = file_path
+                .canonicalize()
+                .with_context(|| format!("Failed to find {}", file_path.display()))?;
+            Some(cache::snippet_dir_name(&file_path))
+        }
+        None => None,
+    };
+
+    let entries = cache::entries()?
+        .into_iter()
+        .filter(|(x, _)| dir_name.as_ref().is_none_or(|dir_name| x == dir_name))
+        .filter(|(_, x)| package_names.is_empty() || package_names.contains(x))
+        .collect::<Vec<(String, String)>>();
+    for package_name in package_names {
+        if !entries.iter().any(|(_, x)| x == package_name) {
+            let output_text = format!("No cache of the {package_name} package to clean.")
+                .bright_yellow()
+                .bold();
+            print_line(output_text);
+        }
+    }
+
+    let mut freed = 0;
+    for (dir_name, entry_name) in entries {
+        let cache = Cache::in_snippet_dir(&dir_name, &entry_name);
+        let (removed_dir, artifacts) = match profile {
+            None => (cache.dir.clone(), "cache"),
+            Some(Profile::Debug) => (cache.target_dir.join("debug"), "debug artifacts"),
+            Some(Profile::Release) => (cache.target_dir.join("release"), "release artifacts"),
+        };
+        if !removed_dir.exists() {
+            continue;
+        }
+
+        let snippet = cache::snippet_path(&dir_name).unwrap_or(PathBuf::from(&dir_name));
+        let description = if entry_name == ".workspace" {
+            format!("workspace {artifacts} of {}", snippet.display())
+        } else {
+            format!("{entry_name} {artifacts} of {}", snippet.display())
+        };
+        let size = dir_size(&removed_dir);
+
+        let removed = Event::Removed {
+            description: description.clone(),
+            size,
+            dry_run,
+        };
+
+        if dry_run {
+            print_line(format!(
+                "Would remove the {description} ({})",
+                format_size(size)
+            ));
+            emit(removed);
+            freed += size;
+            continue;
+        }
+
+        let Some(_lock) = cache.try_lock()? else {
+            let output_text = format!("Skip cleaning the {description} because it is in use.")
+                .bright_yellow()
+                .bold();
+            print_line(output_text);
+            continue;
+        };
+        if let Some(profile) = profile {
+            discard_identity_hash(&cache.dir, profile)?;
+        }
+        print_line(format!("Remove the {description} ({})", format_size(size)));
+        fs::remove_dir_all(&removed_dir)?;
+        freed += size;
+        emit(removed);
+
+        if profile.is_none() {
+            cache::remove_snippet_dir_if_empty(&dir_name)?;
+        }
+    }
+
+    let output_text = if dry_run {
+        format!("Would free {}", format_size(freed))
+    } else {
+        format!("Freed {}", format_size(freed))
+    }
+    .bright_green()
+    .bold();
+    print_line(output_text);
+    emit(Event::Freed {
+        size: freed,
+        dry_run,
+    });
+
+    Ok(())
+}
+
+// Identity
+
+/// Prints the inputs of the identity hash of the package, built with the flags of the options.
+pub(crate) fn show_identity(
+    file_path: &Path,
+    package: &Package,
+    packages: &[Package],
+    options: &BuildOptions,
+) -> Result<()> {
+    let cache = Cache::new(file_path, &package.name)?;
+    let identity = Identity::new(
+        package,
+        packages,
+        &cache,
+        &identity_cargo_args(package, options)?,
+    );
+
+    let hash = identity.hash();
+    let state = if cache.is_same_identity_hash(&hash, Profile::Debug) {
+        "cached"
+    } else if cache.identity_hash(Profile::Debug).is_some() {
+        "changed since the last build"
+    } else {
+        "not built"
+    };
+    print_line(format!("{} {} ({state})", package.name.bold(), &hash[..16]));
+
+    let width = identity
+        .components
+        .iter()
+        .map(|x| x.name.len())
+        .max()
+        .unwrap_or_default();
+    for component in &identity.components {
+        print_line(format!(
+            "    {:<width$}  {}  {}",
+            component.name,
+            &component.hash[..16],
+            component.description
+        ));
+    }
+    emit(Event::Identity {
+        package: package.name.clone(),
+        hash,
+        state,
+        components: identity
+            .components
+            .iter()
+            .map(|x| IdentityComponent {
+                name: x.name.clone(),
+                hash: x.hash.clone(),
+                description: x.description.clone(),
+            })
+            .collect(),
+    });
+
+    Ok(())
+}
+
+// Explain
+
+/// Prints why the package would be rebuilt, comparing its inputs with those of its last successful build.
+pub(crate) fn explain(
+    file_path: &Path,
+    package: &Package,
+    packages: &[Package],
+    workspace: bool,
+    profile: Profile,
+    options: &BuildOptions,
+) -> Result<()> {
+    let cache = if workspace {
+        Cache::in_workspace(file_path, &package.name)?
+    } else {
+        Cache::new(file_path, &package.name)?
+    };
+    let cargo_args = identity_cargo_args(package, options)?;
+    let reasons =
+        Identity::new(package, packages, &cache, &cargo_args).rebuild_reasons(&cache, profile);
+
+    if reasons.is_empty() {
+        print_line(format!("{} is cached.", package.name.bold()));
+    } else {
+        print_line(format!("{} is rebuilt because:", package.name.bold()));
+        explain_rebuild(&reasons);
+    }
+    emit(Event::Explanation {
+        package: package.name.clone(),
+        reasons,
+    });
 
     Ok(())
 }
 
 // ListCaches
 
-pub(crate) fn list_caches(file_name: &str, package: &Package) -> Result<()> {
-    let cache = Cache::new(file_name, &package.name);
+pub(crate) fn list_caches(file_path: &Path, package: &Package, packages: &[Package]) -> Result<()> {
+    let cache = Cache::new(file_path, &package.name)?;
 
-    let new_identity_hash = package.identity_hash();
-    if cache.is_same_identity_hash(&new_identity_hash) {
-        println!("{}", package.name);
+    let cargo_args = identity_cargo_args(package, &BuildOptions::default())?;
+    let identity_hash = Identity::new(package, packages, &cache, &cargo_args).hash();
+    if cache.is_same_identity_hash(&identity_hash, Profile::Debug) {
+        list_package(package);
     }
 
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn runs_stop_at_a_package_which_failed() {
+    use std::os::unix::process::ExitStatusExt;
+
+    const INPUT: &str = r#"//# [package]
+//# name = "app"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//# name = "other"
+
+fn main() {}
+"#;
+    let (packages, _) = package::parse_snippet(Path::new("snippet.rs"), INPUT);
+    let run_all = |options: &BuildOptions| {
+        let mut ran = Vec::new();
+        let mut statuses = Vec::new();
+        let result = for_each_package(&packages, "run", options, |package| {
+            ran.push(package.name.clone());
+            let code = if package.name == "app" { 3 } else { 0 };
+            let status = RunStatus {
+                exit_status: process::ExitStatus::from_raw(code << 8),
+            };
+            record_run(&mut statuses, status, package, options)
+        });
+        (ran, exit_code_of_runs(result, &statuses))
+    };
+
+    let (ran, exit_code) = run_all(&BuildOptions::default());
+    assert_eq!(ran, ["app"]);
+    let error = exit_code.unwrap_err();
+    assert_eq!(error.downcast_ref::<RunFailure>().unwrap().exit_code, 3);
+
+    let (ran, exit_code) = run_all(&BuildOptions {
+        keep_going: true,
+        ..Default::default()
+    });
+    assert_eq!(ran, ["app", "other"]);
+    assert_eq!(exit_code.unwrap(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn exit_codes_of_runs() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let status = |raw| RunStatus {
+        exit_status: process::ExitStatus::from_raw(raw),
+    };
+    assert_eq!(status(3 << 8).exit_code(), 3);
+    assert_eq!(status(3 << 8).description(), "exited with code 3");
+    // Killed by SIGKILL.
+    assert_eq!(status(9).exit_code(), 137);
+    assert_eq!(status(9).description(), "killed by signal 9");
+
+    let statuses = [status(0), status(3 << 8), status(9)];
+    assert_eq!(exit_code_of_runs(Ok(()), &statuses).unwrap(), 3);
+    assert_eq!(exit_code_of_runs(Ok(()), &statuses[..1]).unwrap(), 0);
+    assert_eq!(exit_code_of_runs(Ok(()), &[]).unwrap(), 0);
+
+    let error = exit_code_of_runs(Err(anyhow::anyhow!("failed")), &statuses).unwrap_err();
+    assert_eq!(error.downcast_ref::<RunFailure>().unwrap().exit_code, 3);
+    let error = exit_code_of_runs(Err(anyhow::anyhow!("failed")), &statuses[..1]).unwrap_err();
+    assert!(error.downcast_ref::<RunFailure>().is_none());
+}
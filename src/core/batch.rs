@@ -0,0 +1,210 @@
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+
+use super::{
+    journal::{bail_if_interrupted, is_interrupted},
+    output::{emit, Event},
+    package::Package,
+    BuildOptions,
+};
+
+/// Outcome of one package of a multi-package command, none if it was skipped.
+type Outcome<'a> = (&'a str, Option<Result<()>>);
+
+/// Applies `f` to each package in order.
+/// Unless `--keep-going` is given, the packages after the first failure are skipped.
+/// Every failure is reported in the returned error.
+pub(crate) fn for_each_package<'a>(
+    packages: impl IntoIterator<Item = &'a Package>,
+    action: &str,
+    options: &BuildOptions,
+    mut f: impl FnMut(&'a Package) -> Result<()>,
+) -> Result<()> {
+    let mut failed = false;
+    let outcomes = packages
+        .into_iter()
+        .map(|package| {
+            if failed && !options.keep_going || is_interrupted() {
+                return (package.name.as_str(), None);
+            }
+            let result = f(package);
+            failed |= result.is_err();
+            record_failure(package, &result, options);
+            (package.name.as_str(), Some(result))
+        })
+        .collect();
+
+    report_failures(action, outcomes)
+}
+
+/// Applies `f` to the packages in parallel, `--jobs` of them at once.
+/// Unless `--keep-going` is given, no package is started after the first failure.
+pub(crate) fn par_for_each_package(
+    packages: &[&Package],
+    action: &str,
+    options: &BuildOptions,
+    f: impl Fn(&Package) -> Result<()> + Sync,
+) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrency(packages.len(), options))
+        .build()?;
+    let failed = AtomicBool::new(false);
+    let outcomes = pool.install(|| {
+        packages
+            .par_iter()
+            .map(|package| {
+                if failed.load(Ordering::SeqCst) && !options.keep_going || is_interrupted() {
+                    return (package.name.as_str(), None);
+                }
+                let result = f(package);
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                record_failure(package, &result, options);
+                (package.name.as_str(), Some(result))
+            })
+            .collect()
+    });
+
+    report_failures(action, outcomes)
+}
+
+fn record_failure(package: &Package, result: &Result<()>, options: &BuildOptions) {
+    let Err(error) = result else {
+        return;
+    };
+    if let Some(summary) = &options.summary {
+        summary.failed(&package.name);
+    }
+    emit(Event::PackageFailed {
+        package: package.name.clone(),
+        message: format!("{error:#}"),
+    });
+}
+
+/// The options of packages processed by `par_for_each_package`,
+/// where cargo gets an equal share of the CPUs in each package.
+pub(crate) fn parallel_options(packages: &[&Package], options: &BuildOptions) -> BuildOptions {
+    BuildOptions {
+        cargo_jobs: Some((available_cpus() / concurrency(packages.len(), options)).max(1)),
+        ..options.clone()
+    }
+}
+
+/// Number of packages processed at once: `--jobs`, or one per CPU.
+fn concurrency(packages: usize, options: &BuildOptions) -> usize {
+    options
+        .jobs
+        .unwrap_or_else(available_cpus)
+        .min(packages)
+        .max(1)
+}
+
+fn available_cpus() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+fn report_failures(action: &str, mut outcomes: Vec<Outcome>) -> Result<()> {
+    bail_if_interrupted()?;
+    if let [(_, Some(Err(_)))] = outcomes.as_slice() {
+        // The error of a single package needs no report.
+        return outcomes.pop().unwrap().1.unwrap();
+    }
+
+    let failures = outcomes
+        .iter()
+        .filter_map(|(name, outcome)| match outcome {
+            Some(Err(error)) => Some(format!("    {name}: {error:#}")),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "Failed to {action} {} of {} packages:\n{}",
+        failures.len(),
+        outcomes.len(),
+        failures.join("\n")
+    );
+    let skipped = outcomes.iter().filter(|(_, x)| x.is_none()).count();
+    if skipped > 0 {
+        let packages = if skipped == 1 { "package" } else { "packages" };
+        message.push_str(&format!(
+            "\nSkipped {skipped} {packages} after the failure, use --keep-going to continue."
+        ));
+    }
+    bail!(message)
+}
+
+#[test]
+fn report_failures_of_packages() {
+    let error = report_failures(
+        "build",
+        vec![("app", Some(Err(anyhow::anyhow!("Failed to build app."))))],
+    )
+    .unwrap_err();
+    assert_eq!(error.to_string(), "Failed to build app.");
+
+    assert!(report_failures("build", vec![("app", Some(Ok(()))), ("lib", None)]).is_ok());
+
+    let error = report_failures(
+        "run",
+        vec![
+            ("app", Some(Ok(()))),
+            ("bad", Some(Err(anyhow::anyhow!("Exited with 1.")))),
+            ("later", None),
+            ("last", None),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Failed to run 1 of 4 packages:
+    bad: Exited with 1.
+Skipped 2 packages after the failure, use --keep-going to continue."
+    );
+}
+
+#[test]
+fn share_cpus_between_packages() {
+    let options = |jobs| BuildOptions {
+        jobs,
+        ..BuildOptions::default()
+    };
+    assert_eq!(concurrency(3, &options(Some(8))), 3);
+    assert_eq!(concurrency(3, &options(Some(2))), 2);
+    assert_eq!(concurrency(1, &options(None)), 1);
+    assert_eq!(concurrency(0, &options(None)), 1);
+
+    const INPUT: &str = r#"//# [package]
+//# name = "app"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//# name = "other"
+
+fn main() {}
+"#;
+    let (packages, _) = super::package::parse_snippet(std::path::Path::new("snippet.rs"), INPUT);
+    let packages = packages.iter().collect::<Vec<_>>();
+    let cargo_jobs =
+        |packages: &[&Package], jobs| parallel_options(packages, &options(jobs)).cargo_jobs;
+    // More jobs than packages leave the CPUs of the unused jobs to cargo.
+    assert_eq!(
+        cargo_jobs(&packages, Some(8)),
+        Some((available_cpus() / 2).max(1))
+    );
+    assert_eq!(cargo_jobs(&packages[..1], Some(8)), Some(available_cpus()));
+    assert_eq!(cargo_jobs(&packages[..1], None), Some(available_cpus()));
+}
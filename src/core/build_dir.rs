@@ -0,0 +1,200 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use tiny_ansi::TinyAnsi;
+
+use super::{output::print_line, package::Package};
+
+/// A project directory kept in the cache between builds.
+///
+/// Its path never changes and only the files whose contents changed are rewritten,
+/// so that cargo's fingerprints of the local crates stay valid.
+pub(super) struct BuildDir {
+    pub(super) package_dir: PathBuf,
+}
+
+impl BuildDir {
+    pub(super) fn new<P: AsRef<Path>>(
+        project_dir: P,
+        package: &Package,
+        packages: &[Package],
+    ) -> Result<BuildDir> {
+        let project_dir = project_dir.as_ref();
+
+        let mut files = package_files(&package.name, package, package.manifest());
+        // Sibling packages are referenced as `../<name>`.
+        for dependency in package.dependencies(packages) {
+            files.extend(package_files(
+                &dependency.name,
+                dependency,
+                dependency.manifest(),
+            ));
+        }
+        sync(project_dir, &files)?;
+
+        Ok(BuildDir {
+            package_dir: project_dir.join(&package.name),
+        })
+    }
+
+    /// Creates every package as a member of one workspace.
+    /// `package_dir` is the root of the workspace.
+    pub(super) fn workspace<P: AsRef<Path>>(
+        project_dir: P,
+        packages: &[Package],
+    ) -> Result<BuildDir> {
+        let project_dir = project_dir.as_ref();
+
+        let mut files = Vec::new();
+        let mut profiles: Vec<(&str, toml_edit::Item)> = Vec::new();
+        for package in packages {
+            // Cargo ignores the profiles of members, they are moved to the workspace root.
+            let mut manifest = package
+                .manifest()
+                .parse::<toml_edit::Document>()
+                .expect("The manifest is already parsed.");
+            manifest.remove("workspace");
+            if let Some(profile) = manifest.remove("profile") {
+                profiles.push((&package.name, profile));
+            }
+            files.extend(package_files(&package.name, package, manifest.to_string()));
+        }
+
+        let mut manifest = toml_edit::Document::new();
+        let members = packages
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<toml_edit::Array>();
+        manifest["workspace"]["members"] = toml_edit::value(members);
+        manifest["workspace"]["resolver"] = toml_edit::value("2");
+        if let Some((name, profile)) = profiles.first() {
+            let differs = profiles
+                .iter()
+                .any(|(_, x)| x.to_string() != profile.to_string());
+            if differs {
+                let output_text = format!(
+                    "Packages have different profiles, the profile of the {name} package is used for the workspace."
+                )
+                .bright_yellow()
+                .bold();
+                print_line(output_text);
+            }
+            manifest["profile"] = profile.clone();
+        }
+        files.push((PathBuf::from("Cargo.toml"), manifest.to_string()));
+        sync(project_dir, &files)?;
+
+        Ok(BuildDir {
+            package_dir: project_dir.to_owned(),
+        })
+    }
+}
+
+/// Lists the files of the package, relative to the project directory.
+fn package_files(dir: &str, package: &Package, manifest: String) -> Vec<(PathBuf, String)> {
+    let dir = Path::new(dir);
+
+    let mut files = vec![
+        (dir.join("Cargo.toml"), manifest),
+        (dir.join(package.src_path()), package.src.clone()),
+    ];
+    for file in &package.files {
+        files.push((dir.join(&file.path), file.content.clone()));
+    }
+    files
+}
+
+/// Makes the project directory contain exactly the given files.
+/// Files are only written when their contents differ, to preserve their mtime,
+/// and `Cargo.lock` files are kept so that dependencies stay locked.
+fn sync(project_dir: &Path, files: &[(PathBuf, String)]) -> Result<()> {
+    for (path, content) in files {
+        let path = project_dir.join(path);
+        if fs::read(&path).is_ok_and(|x| x == content.as_bytes()) {
+            continue;
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, content)?;
+    }
+
+    let paths = files
+        .iter()
+        .map(|(path, _)| project_dir.join(path))
+        .collect::<HashSet<PathBuf>>();
+    remove_stale_files(project_dir, &paths)?;
+
+    Ok(())
+}
+
+/// Removes the files that are not in `paths`, and the directories left empty.
+fn remove_stale_files(dir: &Path, paths: &HashSet<PathBuf>) -> Result<()> {
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_stale_files(&path, paths)?;
+            if path.read_dir()?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        } else if !paths.contains(&path) && !path.ends_with("Cargo.lock") {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sync_rewrites_only_changed_files() {
+    use std::{env, process, time::SystemTime};
+
+    let project_dir = env::temp_dir().join(format!("rspit-test-build-dir-{}", process::id()));
+    let file = |path: &str, content: &str| (PathBuf::from(path), content.to_owned());
+    sync(
+        &project_dir,
+        &[
+            file("app/Cargo.toml", "[package]"),
+            file("app/src/main.rs", "fn main() {}"),
+            file("app/src/parser/ast.rs", "pub struct Ast;"),
+        ],
+    )
+    .unwrap();
+    fs::write(project_dir.join("app/Cargo.lock"), "version = 3").unwrap();
+    let long_ago = SystemTime::UNIX_EPOCH;
+    for path in ["app/Cargo.toml", "app/src/main.rs"] {
+        fs::File::options()
+            .write(true)
+            .open(project_dir.join(path))
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+    }
+
+    sync(
+        &project_dir,
+        &[
+            file("app/Cargo.toml", "[package]\nname = \"app\""),
+            file("app/src/main.rs", "fn main() {}"),
+        ],
+    )
+    .unwrap();
+    let modified = |path: &str| {
+        fs::metadata(project_dir.join(path))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    assert_eq!(modified("app/src/main.rs"), long_ago);
+    assert_ne!(modified("app/Cargo.toml"), long_ago);
+    assert_eq!(
+        fs::read_to_string(project_dir.join("app/Cargo.toml")).unwrap(),
+        "[package]\nname = \"app\""
+    );
+    assert!(!project_dir.join("app/src/parser").exists());
+    assert!(project_dir.join("app/Cargo.lock").exists());
+
+    fs::remove_dir_all(project_dir).unwrap();
+}
@@ -1,87 +1,563 @@
 use std::{
-    env, fs,
-    path::{Path, PathBuf},
+    env,
+    ffi::OsString,
+    fs,
+    path::{self, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_ansi::TinyAnsi;
 
+use super::{
+    cargo::Executable, config::config, identity::Identity, journal::discard_identity_hashes,
+    lock::Lock, output::print_line, progress::Progress, utils::move_dir, Profile,
+};
+
+/// The cache is placed in the first of:
+/// `PIT_CACHE_DIR`, `cache-dir` of the config file, the user cache directory and the temporary directory.
 pub(super) fn root_dir() -> PathBuf {
+    select_root_dir(
+        env::var_os("PIT_CACHE_DIR"),
+        config().cache_dir.as_deref(),
+        dirs::cache_dir(),
+    )
+}
+
+fn select_root_dir(
+    env_dir: Option<OsString>,
+    config_dir: Option<&Path>,
+    user_cache_dir: Option<PathBuf>,
+) -> PathBuf {
+    if let Some(dir) = env_dir.filter(|x| !x.is_empty()) {
+        // Made absolute since cargo runs in the package directory.
+        return path::absolute(&dir).unwrap_or(PathBuf::from(dir));
+    }
+    if let Some(dir) = config_dir {
+        return dir.to_owned();
+    }
+    user_cache_dir
+        .map(|x| x.join("pit"))
+        .unwrap_or_else(legacy_root_dir)
+}
+
+/// Where older versions of pit kept the cache.
+fn legacy_root_dir() -> PathBuf {
     env::temp_dir().join("pit")
 }
 
+/// Lock files are kept apart from the entries, so that removing an entry never removes a held lock.
+fn lock_dir() -> PathBuf {
+    root_dir().join(".locks")
+}
+
+/// Directory holding the caches of a snippet.
+///
+/// It is named after the file stem and a hash of the canonical path of the snippet,
+/// so that snippets with the same file name in different directories do not share caches.
+/// The path itself is recorded in `snippet_path` to tell which snippet the caches belong to.
+pub(super) fn snippet_dir(file_path: &Path) -> Result<String> {
+    let file_path = file_path.canonicalize()?;
+    let stem = file_path.file_stem().unwrap().to_string_lossy();
+    let dir_name = snippet_dir_name(&file_path);
+
+    let dir = root_dir().join(&dir_name);
+    if !dir.exists() {
+        migrate(&root_dir(), &legacy_root_dir(), &dir_name, &stem, &dir)?;
+    }
+
+    let index = dir.join("snippet_path");
+    if snippet_path(&dir_name).as_deref() != Some(file_path.as_path()) {
+        fs::create_dir_all(&dir)?;
+        fs::write(index, file_path.to_string_lossy().as_bytes())?;
+    }
+
+    Ok(dir_name)
+}
+
+/// Name of the directory of the snippet at the canonical path, which may not exist yet.
+pub(super) fn snippet_dir_name(canonical_path: &Path) -> String {
+    let stem = canonical_path.file_stem().unwrap().to_string_lossy();
+    let hash = format!(
+        "{:x}",
+        Sha256::digest(canonical_path.to_string_lossy().as_bytes())
+    );
+    format!("{stem}-{}", &hash[..16])
+}
+
+/// Path of the snippet whose caches are in the directory, if known.
+pub(super) fn snippet_path(dir_name: &str) -> Option<PathBuf> {
+    fs::read_to_string(root_dir().join(dir_name).join("snippet_path"))
+        .ok()
+        .map(PathBuf::from)
+}
+
+/// Moves the caches of the snippet left by an older pit to its directory.
+///
+/// Caches keyed by the file stem only have their identity hashes discarded,
+/// since they may have been built from another snippet with the same name,
+/// but the dependencies already compiled in them are still reused.
+fn migrate(
+    root_dir: &Path,
+    legacy_root_dir: &Path,
+    dir_name: &str,
+    stem: &str,
+    dir: &Path,
+) -> Result<()> {
+    let is_moved = legacy_root_dir != root_dir;
+
+    if is_moved && legacy_root_dir.join(dir_name).is_dir() {
+        return migrate_dir(&legacy_root_dir.join(dir_name), dir);
+    }
+
+    let old_roots = if is_moved {
+        vec![root_dir, legacy_root_dir]
+    } else {
+        vec![root_dir]
+    };
+    for old_root in old_roots {
+        let old_dir = old_root.join(stem);
+        if !old_dir.is_dir() || old_dir.join("snippet_path").exists() || stem == ".locks" {
+            continue;
+        }
+
+        for entry in old_dir.read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                discard_identity_hashes(&entry.path())?;
+            }
+        }
+        return migrate_dir(&old_dir, dir);
+    }
+
+    Ok(())
+}
+
+fn migrate_dir(old_dir: &Path, dir: &Path) -> Result<()> {
+    let output_text = format!(
+        "Migrate the cache of {} to {}",
+        old_dir.display(),
+        dir.display()
+    )
+    .bright_yellow()
+    .bold();
+    print_line(output_text);
+
+    fs::create_dir_all(dir.parent().unwrap())?;
+    move_dir(old_dir, dir)?;
+
+    Ok(())
+}
+
+/// Names of the snippet directories.
+pub(super) fn snippet_dirs() -> Result<Vec<String>> {
+    let mut dir_names = Vec::new();
+    let Ok(dirs) = root_dir().read_dir() else {
+        return Ok(dir_names);
+    };
+    for dir in dirs {
+        let dir = dir?;
+        let dir_name = dir.file_name().to_string_lossy().into_owned();
+        if dir_name != ".locks" && dir.file_type()?.is_dir() {
+            dir_names.push(dir_name);
+        }
+    }
+
+    Ok(dir_names)
+}
+
+/// Removes the snippet directory once it has no cache entry left.
+pub(super) fn remove_snippet_dir_if_empty(dir_name: &str) -> Result<()> {
+    let dir = root_dir().join(dir_name);
+    for entry in dir.read_dir()? {
+        if entry?.file_type()?.is_dir() {
+            return Ok(());
+        }
+    }
+    fs::remove_dir_all(dir)?;
+
+    Ok(())
+}
+
+/// Snippet directory and entry names of every cache entry, the workspace entries being named `.workspace`.
+pub(super) fn entries() -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for dir_name in snippet_dirs()? {
+        for entry in root_dir().join(&dir_name).read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                entries.push((
+                    dir_name.clone(),
+                    entry.file_name().to_string_lossy().into_owned(),
+                ));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Moves a target directory left by an older pit in its temporary build directory
+/// back to the only entry of the package which has none, after discarding the identity hashes of the entry.
+/// Returns false if there is no such entry, several of them, or if it is in use.
+pub(super) fn restore_target(package_name: &str, target_dir: &Path) -> Result<bool> {
+    let mut candidates = Vec::new();
+    for (dir_name, name) in entries()? {
+        if name == package_name {
+            let cache = Cache::in_snippet_dir(&dir_name, &name);
+            candidates.push((cache.dir.clone(), Some(cache)));
+        }
+    }
+    if legacy_root_dir() != root_dir() {
+        if let Ok(old_dirs) = legacy_root_dir().read_dir() {
+            for old_dir in old_dirs {
+                let old_dir = old_dir?.path();
+                if !old_dir.join("snippet_path").exists() {
+                    candidates.push((old_dir.join(package_name), None));
+                }
+            }
+        }
+    }
+    candidates.retain(|(dir, _)| dir.is_dir() && !dir.join("target").exists());
+
+    let [(dir, cache)] = candidates.as_slice() else {
+        return Ok(false);
+    };
+    let _lock = match cache {
+        Some(cache) => match cache.try_lock()? {
+            Some(lock) => Some(lock),
+            None => return Ok(false),
+        },
+        None => None,
+    };
+
+    discard_identity_hashes(dir)?;
+    move_dir(target_dir, dir.join("target"))?;
+
+    Ok(true)
+}
+
 pub(crate) struct Cache {
-    target_dir: PathBuf,
+    pub(super) dir: PathBuf,
+    /// The project is kept here so that its path is stable across builds.
+    pub(super) project_dir: PathBuf,
+    pub(super) target_dir: PathBuf,
+    /// `Cargo.lock` of the package, or of the workspace.
+    pub(super) cargo_lock: PathBuf,
 
-    identity_hash: PathBuf,
-    current_identity_hash: String,
+    lock_file: PathBuf,
+    lock_description: String,
+    /// The package, if the cache is shared by the members of a workspace.
+    workspace_member: Option<String>,
+}
 
-    pub(super) exe_name: String,
-    pub(super) debug_exe: PathBuf,
-    pub(super) release_exe: PathBuf,
+/// The recorded executables, as a `[[executable]]` array.
+#[derive(Serialize, Deserialize)]
+struct Executables {
+    executable: Vec<Executable>,
 }
 
 impl Cache {
-    pub(super) fn new(file_name: &str, package_name: &str) -> Cache {
-        let root = root_dir();
-        let file_name = file_name.to_owned();
-        let package_dir = root.join(file_name).join(package_name);
+    pub(super) fn new(file_path: &Path, package_name: &str) -> Result<Cache> {
+        Ok(Cache::in_snippet_dir(
+            &snippet_dir(file_path)?,
+            package_name,
+        ))
+    }
 
-        let target_dir = package_dir.join("target");
+    /// Cache of an entry of the snippet directory, as listed by `entries`.
+    pub(super) fn in_snippet_dir(dir_name: &str, package_name: &str) -> Cache {
+        let dir = root_dir().join(dir_name).join(package_name);
+        let lock_file = lock_dir().join(dir_name).join(package_name);
+        let cargo_lock = dir.join("project").join(package_name).join("Cargo.lock");
 
-        let identity_hash = package_dir.join("identity_hash");
-        let current_identity_hash = fs::read_to_string(&identity_hash).unwrap_or_default();
+        Cache::with_paths(
+            dir,
+            cargo_lock,
+            lock_file,
+            format!("package {package_name}"),
+            None,
+        )
+    }
+
+    /// Cache of a package built as a member of the workspace of the snippet.
+    /// The directories and the lock are shared by all members.
+    pub(super) fn in_workspace(file_path: &Path, package_name: &str) -> Result<Cache> {
+        Ok(Cache::in_snippet_workspace(
+            &snippet_dir(file_path)?,
+            package_name,
+        ))
+    }
+
+    pub(super) fn in_snippet_workspace(dir_name: &str, package_name: &str) -> Cache {
+        let dir = root_dir().join(dir_name).join(".workspace");
+        let lock_file = lock_dir().join(dir_name).join(".workspace");
+        let snippet = snippet_path(dir_name).unwrap_or(PathBuf::from(dir_name));
+        let cargo_lock = dir.join("project").join("Cargo.lock");
 
-        let exe_name = if cfg!(windows) {
-            format!("{package_name}.exe")
-        } else {
-            package_name.to_owned()
-        };
-        let debug_exe = target_dir.join("debug").join(&exe_name);
-        let release_exe = target_dir.join("release").join(&exe_name);
+        Cache::with_paths(
+            dir,
+            cargo_lock,
+            lock_file,
+            format!("workspace {}", snippet.display()),
+            Some(package_name.to_owned()),
+        )
+    }
+
+    #[cfg(test)]
+    pub(super) fn in_dir(dir: PathBuf) -> Cache {
+        let cargo_lock = dir.join("Cargo.lock");
+        let lock_file = dir.join("lock");
+        Cache::with_paths(dir, cargo_lock, lock_file, "test".to_owned(), None)
+    }
+
+    fn with_paths(
+        dir: PathBuf,
+        cargo_lock: PathBuf,
+        lock_file: PathBuf,
+        lock_description: String,
+        workspace_member: Option<String>,
+    ) -> Cache {
+        let project_dir = dir.join("project");
+        let target_dir = dir.join("target");
 
         Cache {
+            dir,
+            project_dir,
             target_dir,
+            cargo_lock,
 
-            identity_hash,
-            current_identity_hash,
-
-            exe_name,
-            debug_exe,
-            release_exe,
+            lock_file,
+            lock_description,
+            workspace_member,
         }
     }
 
-    pub(super) fn restore<P: AsRef<Path>>(&self, target_dir: P) -> Result<()> {
-        fs::create_dir_all(&self.target_dir)?;
-        // Restore target directory from cache.
-        fs::rename(&self.target_dir, &target_dir)?;
+    /// Locks the entry against other pit processes for as long as the returned lock lives.
+    /// The entry is marked as used at the same time.
+    pub(super) fn lock(&self, progress: Option<&Progress>) -> Result<Lock> {
+        let lock = Lock::acquire(&self.lock_file, &self.lock_description, progress)?;
 
-        Ok(())
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join("last_used"), now.as_secs().to_string())?;
+
+        Ok(lock)
     }
 
-    pub(super) fn store<P: AsRef<Path>>(&self, target_dir: P) -> Result<()> {
-        if self.target_dir.exists() {
-            bail!("Failed to handle cache.")
+    pub(super) fn try_lock(&self) -> Result<Option<Lock>> {
+        Lock::try_acquire(&self.lock_file)
+    }
+
+    /// When the entry was last locked, or modified for entries of older versions of pit.
+    pub(super) fn last_used(&self) -> Option<SystemTime> {
+        let last_used = fs::read_to_string(self.dir.join("last_used"))
+            .ok()
+            .and_then(|x| x.trim().parse().ok())
+            .map(|x| UNIX_EPOCH + Duration::from_secs(x));
+        last_used.or_else(|| fs::metadata(&self.dir).and_then(|x| x.modified()).ok())
+    }
+
+    /// Hash of the identity of the last build of the profile, discarded when it may not match the artifacts anymore.
+    fn identity_hash_path(&self, profile: Profile) -> PathBuf {
+        let path = self.dir.join(identity_hash_name(profile));
+        match &self.workspace_member {
+            Some(member) => path.join(member),
+            None => path,
+        }
+    }
+
+    /// A record of the last successful build, in a directory with one file per member for a workspace.
+    fn record_path(&self, name: &str) -> PathBuf {
+        match &self.workspace_member {
+            Some(member) => self.dir.join(name).join(format!("{member}.toml")),
+            None => self.dir.join(format!("{name}.toml")),
         }
-        // Store target directory in cache.
-        fs::rename(target_dir, &self.target_dir)?;
+    }
+
+    /// Identity of the last successful build of the profile, kept to tell what changed since.
+    fn identity_path(&self, profile: Profile) -> PathBuf {
+        self.record_path(match profile {
+            Profile::Debug => "identity",
+            Profile::Release => "release_identity",
+        })
+    }
+
+    fn executables_path(&self, profile: Profile) -> PathBuf {
+        self.record_path(match profile {
+            Profile::Debug => "executables",
+            Profile::Release => "release_executables",
+        })
+    }
+
+    /// Executables of the last successful build of the profile, none if they were not recorded.
+    pub(super) fn executables(&self, profile: Profile) -> Option<Vec<Executable>> {
+        let content = fs::read_to_string(self.executables_path(profile)).ok()?;
+        toml::from_str::<Executables>(&content)
+            .ok()
+            .map(|x| x.executable)
+    }
+
+    /// Recorded before the identity, which marks the build as finished.
+    pub(super) fn write_executables(
+        &self,
+        executables: Vec<Executable>,
+        profile: Profile,
+    ) -> Result<()> {
+        let executables_path = self.executables_path(profile);
+        fs::create_dir_all(executables_path.parent().unwrap())?;
+        fs::write(
+            executables_path,
+            toml::to_string(&Executables {
+                executable: executables,
+            })?,
+        )?;
 
         Ok(())
     }
 
-    pub(super) fn write_identity_hash(&self, new_identity_hash: &str) -> Result<()> {
-        fs::write(&self.identity_hash, new_identity_hash)?;
+    pub(super) fn identity_hash(&self, profile: Profile) -> Option<String> {
+        fs::read_to_string(self.identity_hash_path(profile)).ok()
+    }
+
+    /// Records the identity of a successful build.
+    pub(super) fn write_identity(&self, identity: &Identity, profile: Profile) -> Result<()> {
+        let identity_path = self.identity_path(profile);
+        fs::create_dir_all(identity_path.parent().unwrap())?;
+        fs::write(identity_path, toml::to_string(identity)?)?;
+
+        let identity_hash_path = self.identity_hash_path(profile);
+        fs::create_dir_all(identity_hash_path.parent().unwrap())?;
+        fs::write(identity_hash_path, identity.hash())?;
 
         Ok(())
     }
 
-    pub(super) fn is_same_identity_hash(&self, new_identity_hash: &str) -> bool {
-        new_identity_hash == self.current_identity_hash
+    pub(super) fn last_identity(&self, profile: Profile) -> Option<Identity> {
+        let content = fs::read_to_string(self.identity_path(profile)).ok()?;
+        toml::from_str(&content).ok()
     }
 
-    pub(super) fn delete_identity_hash(&self) -> Result<()> {
-        fs::remove_file(&self.identity_hash)?;
+    /// The identity hash is not trusted while a transaction on the cache is unfinished,
+    /// nor when the executables of the build were not recorded.
+    pub(super) fn is_same_identity_hash(&self, new_identity_hash: &str, profile: Profile) -> bool {
+        if self.dir.join("journal").exists() || !self.executables_path(profile).exists() {
+            return false;
+        }
+        self.identity_hash(profile)
+            .is_some_and(|x| x == new_identity_hash)
+    }
+
+    pub(super) fn delete_identity_hash(&self, profile: Profile) -> Result<()> {
+        let identity_hash_path = self.identity_hash_path(profile);
+        if identity_hash_path.exists() {
+            fs::remove_file(identity_hash_path)?;
+        }
 
         Ok(())
     }
 }
+
+/// Name of the identity hash of the profile in the cache entry,
+/// a file or, for a workspace, a directory with one file per member.
+pub(super) fn identity_hash_name(profile: Profile) -> &'static str {
+    match profile {
+        Profile::Debug => "identity_hash",
+        Profile::Release => "release_identity_hash",
+    }
+}
+
+#[test]
+fn snippet_dir_names() {
+    let name = snippet_dir_name(Path::new("/a/snippet.rs"));
+    assert_eq!(name, snippet_dir_name(Path::new("/a/snippet.rs")));
+    assert_ne!(name, snippet_dir_name(Path::new("/b/snippet.rs")));
+    assert!(name.starts_with("snippet-"));
+    assert_eq!(name.len(), "snippet-".len() + 16);
+}
+
+#[test]
+fn cache_dir_precedence() {
+    let env_dir = Some(OsString::from("/env"));
+    let config_dir = Some(Path::new("/config"));
+    let user_cache_dir = Some(PathBuf::from("/cache"));
+    assert_eq!(
+        select_root_dir(env_dir, config_dir, user_cache_dir.clone()),
+        Path::new("/env")
+    );
+    assert_eq!(
+        select_root_dir(Some(OsString::new()), config_dir, user_cache_dir.clone()),
+        Path::new("/config")
+    );
+    assert_eq!(
+        select_root_dir(None, None, user_cache_dir),
+        Path::new("/cache/pit")
+    );
+    assert_eq!(select_root_dir(None, None, None), legacy_root_dir());
+    assert!(select_root_dir(Some(OsString::from("relative")), None, None).is_absolute());
+}
+
+#[test]
+fn migrate_caches_of_older_versions() {
+    use std::process;
+
+    let temp_dir = env::temp_dir().join(format!("rspit-test-migrate-{}", process::id()));
+    let (root_dir, legacy_root_dir) = (temp_dir.join("root"), temp_dir.join("legacy"));
+
+    // Keyed by the file stem only, the identity hash may belong to another snippet.
+    let old_dir = root_dir.join("stem");
+    fs::create_dir_all(old_dir.join("app/target")).unwrap();
+    fs::write(old_dir.join("app/identity_hash"), "hash").unwrap();
+    let dir = root_dir.join("stem-0123456789abcdef");
+    migrate(
+        &root_dir,
+        &legacy_root_dir,
+        "stem-0123456789abcdef",
+        "stem",
+        &dir,
+    )
+    .unwrap();
+    assert!(!old_dir.exists());
+    assert!(dir.join("app/target").is_dir());
+    assert!(!dir.join("app/identity_hash").exists());
+
+    // Already keyed by the canonical path in the legacy root, the identity hash is kept.
+    let old_dir = legacy_root_dir.join("other-0123456789abcdef");
+    fs::create_dir_all(old_dir.join("app")).unwrap();
+    fs::write(old_dir.join("app/identity_hash"), "hash").unwrap();
+    let dir = root_dir.join("other-0123456789abcdef");
+    migrate(
+        &root_dir,
+        &legacy_root_dir,
+        "other-0123456789abcdef",
+        "other",
+        &dir,
+    )
+    .unwrap();
+    assert!(!old_dir.exists());
+    assert!(dir.join("app/identity_hash").exists());
+
+    // The directory of another snippet with the same stem is left alone.
+    let dir = root_dir.join("other-fedcba9876543210");
+    fs::write(
+        root_dir.join("other-0123456789abcdef/snippet_path"),
+        "/other.rs",
+    )
+    .unwrap();
+    fs::create_dir_all(root_dir.join("other")).unwrap();
+    fs::write(root_dir.join("other/snippet_path"), "/a/other.rs").unwrap();
+    migrate(
+        &root_dir,
+        &legacy_root_dir,
+        "other-fedcba9876543210",
+        "other",
+        &dir,
+    )
+    .unwrap();
+    assert!(root_dir.join("other").exists());
+    assert!(!dir.exists());
+
+    fs::remove_dir_all(temp_dir).unwrap();
+}
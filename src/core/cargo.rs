@@ -1,13 +1,65 @@
-use std::{path::Path, process};
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process,
+};
 
 use anyhow::{bail, Result};
+use serde_derive::{Deserialize, Serialize};
 
-pub(super) fn check<P: AsRef<Path>>(package_dir: P, quiet: bool) -> Result<()> {
+use super::progress::Progress;
+
+/// A binary built by cargo, as reported in its `compiler-artifact` messages.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct Executable {
+    pub(super) package: String,
+    /// Name of the `[[bin]]` target.
+    pub(super) name: String,
+    pub(super) path: PathBuf,
+}
+
+/// A build shown in the progress display, whose output goes to a log file instead of the terminal.
+pub(super) struct Tracker<'a> {
+    pub(super) progress: &'a Progress,
+    pub(super) package: &'a str,
+    pub(super) log: PathBuf,
+}
+
+/// The part of cargo's JSON messages pit reads.
+#[derive(Deserialize)]
+struct Message {
+    reason: String,
+    manifest_path: Option<PathBuf>,
+    target: Option<Target>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    name: String,
+    kind: Vec<String>,
+}
+
+pub(super) fn check<P: AsRef<Path>, Q: AsRef<Path>>(
+    package_dir: P,
+    target_dir: Q,
+    quiet: bool,
+    args: &[String],
+    tracker: Option<&Tracker>,
+) -> Result<()> {
     let mut command = process::Command::new("cargo");
     command.arg("check");
+    command.arg("--target-dir").arg(target_dir.as_ref());
     if quiet {
         command.arg("--quiet");
     }
+    command.args(args);
+    if tracker.is_some() {
+        command.current_dir(&package_dir);
+        build_executables(command, tracker)?;
+        return Ok(());
+    }
     let exit_status = command.current_dir(&package_dir).spawn()?.wait()?;
 
     if !exit_status.success() {
@@ -17,20 +69,192 @@ pub(super) fn check<P: AsRef<Path>>(package_dir: P, quiet: bool) -> Result<()> {
     Ok(())
 }
 
-pub(super) fn build<P: AsRef<Path>>(package_dir: P, release: bool, quiet: bool) -> Result<()> {
+pub(super) fn build<P: AsRef<Path>, Q: AsRef<Path>>(
+    package_dir: P,
+    target_dir: Q,
+    release: bool,
+    quiet: bool,
+    args: &[String],
+    tracker: Option<&Tracker>,
+) -> Result<Vec<Executable>> {
     let mut command = process::Command::new("cargo");
     command.arg("build");
+    command.arg("--target-dir").arg(target_dir.as_ref());
     if release {
         command.arg("--release");
     }
     if quiet {
         command.arg("--quiet");
     }
-    let exit_status = command.current_dir(&package_dir).spawn()?.wait()?;
+    command.args(args);
+    command.current_dir(&package_dir);
+
+    build_executables(command, tracker)
+}
+
+/// Runs `cargo check` in the workspace for the given members, or all of them if empty.
+pub(super) fn check_workspace<P: AsRef<Path>, Q: AsRef<Path>>(
+    workspace_dir: P,
+    target_dir: Q,
+    members: &[&str],
+    quiet: bool,
+    args: &[String],
+) -> Result<()> {
+    let mut command = process::Command::new("cargo");
+    command.arg("check");
+    command.arg("--target-dir").arg(target_dir.as_ref());
+    select_members(&mut command, members);
+    if quiet {
+        command.arg("--quiet");
+    }
+    command.args(args);
+    let exit_status = command.current_dir(&workspace_dir).spawn()?.wait()?;
 
     if !exit_status.success() {
-        bail!("Failed to build.");
+        bail!("Failed to check.");
     }
 
     Ok(())
 }
+
+/// Runs `cargo build` in the workspace for the given members, or all of them if empty.
+pub(super) fn build_workspace<P: AsRef<Path>, Q: AsRef<Path>>(
+    workspace_dir: P,
+    target_dir: Q,
+    members: &[&str],
+    release: bool,
+    quiet: bool,
+    args: &[String],
+) -> Result<Vec<Executable>> {
+    let mut command = process::Command::new("cargo");
+    command.arg("build");
+    command.arg("--target-dir").arg(target_dir.as_ref());
+    select_members(&mut command, members);
+    if release {
+        command.arg("--release");
+    }
+    if quiet {
+        command.arg("--quiet");
+    }
+    command.args(args);
+    command.current_dir(&workspace_dir);
+
+    build_executables(command, None)
+}
+
+/// Runs the build or check, collecting the executables from cargo's JSON messages on stdout.
+/// Diagnostics are still rendered on stderr, or written to the log of the tracked build.
+fn build_executables(
+    mut command: process::Command,
+    tracker: Option<&Tracker>,
+) -> Result<Vec<Executable>> {
+    command.arg("--message-format=json-render-diagnostics");
+    if let Some(tracker) = tracker {
+        command.stderr(fs::File::create(&tracker.log)?);
+    }
+    let mut child = command.stdout(process::Stdio::piped()).spawn()?;
+
+    let mut executables = Vec::new();
+    let stdout = child.stdout.take().expect("The stdout is piped.");
+    for line in BufReader::new(stdout).lines() {
+        let Some(artifact) = parse_artifact(&line?) else {
+            continue;
+        };
+        if let (Some(tracker), false) = (tracker, artifact.is_build_script) {
+            tracker.progress.compiled(tracker.package);
+        }
+        executables.extend(artifact.executable);
+    }
+
+    if !child.wait()?.success() {
+        let subcommand = command.get_args().next().unwrap_or_default();
+        bail!("Failed to {}.", subcommand.to_string_lossy());
+    }
+    executables.sort_by(|a, b| (&a.package, &a.name).cmp(&(&b.package, &b.name)));
+
+    Ok(executables)
+}
+
+/// A crate compiled by cargo, with the executable it produced if it is a binary.
+struct Artifact {
+    is_build_script: bool,
+    executable: Option<Executable>,
+}
+
+/// Reads a line of cargo's JSON output, none if it is not a `compiler-artifact` message.
+fn parse_artifact(line: &str) -> Option<Artifact> {
+    let message = serde_json::from_str::<Message>(line).ok()?;
+    if message.reason != "compiler-artifact" {
+        return None;
+    }
+    let target = message.target?;
+
+    let executable = match (message.manifest_path, message.executable) {
+        (Some(manifest_path), Some(path)) if target.kind.iter().any(|x| x == "bin") => {
+            // Each package is in a directory named after it.
+            let package = manifest_path
+                .parent()
+                .and_then(|x| x.file_name())
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Some(Executable {
+                package,
+                name: target.name,
+                path,
+            })
+        }
+        _ => None,
+    };
+    Some(Artifact {
+        is_build_script: target.kind.iter().any(|x| x == "custom-build"),
+        executable,
+    })
+}
+
+fn select_members(command: &mut process::Command, members: &[&str]) {
+    if members.is_empty() {
+        command.arg("--workspace");
+    }
+    for member in members {
+        command.args(["--package", member]);
+    }
+}
+
+#[test]
+fn parse_artifacts_of_cargo() {
+    let lib = r#"{"reason":"compiler-artifact","package_id":"path+file:///cache/project/common#0.1.0","manifest_path":"/cache/project/common/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"common","src_path":"/cache/project/common/src/lib.rs"},"filenames":["/cache/target/debug/libcommon.rlib"],"executable":null,"fresh":false}"#;
+    let artifact = parse_artifact(lib).unwrap();
+    assert!(!artifact.is_build_script);
+    assert!(artifact.executable.is_none());
+
+    let bins = [
+        r#"{"reason":"compiler-artifact","manifest_path":"/cache/project/app/Cargo.toml","target":{"kind":["bin"],"name":"app"},"executable":"/cache/target/debug/app","fresh":true}"#,
+        r#"{"reason":"compiler-artifact","manifest_path":"/cache/project/app/Cargo.toml","target":{"kind":["bin"],"name":"tool"},"executable":"/cache/target/debug/tool","fresh":false}"#,
+    ];
+    let executables = bins
+        .iter()
+        .filter_map(|x| parse_artifact(x)?.executable)
+        .map(|x| (x.package, x.name, x.path))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        executables,
+        [
+            (
+                "app".to_owned(),
+                "app".to_owned(),
+                PathBuf::from("/cache/target/debug/app")
+            ),
+            (
+                "app".to_owned(),
+                "tool".to_owned(),
+                PathBuf::from("/cache/target/debug/tool")
+            ),
+        ]
+    );
+
+    let build_script = r#"{"reason":"compiler-artifact","manifest_path":"/registry/libc/Cargo.toml","target":{"kind":["custom-build"],"name":"build-script-build"},"executable":null}"#;
+    assert!(parse_artifact(build_script).unwrap().is_build_script);
+
+    assert!(parse_artifact(r#"{"reason":"build-finished","success":true}"#).is_none());
+    assert!(parse_artifact("   Compiling app v0.1.0").is_none());
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use serde_derive::Deserialize;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings read from `<config dir>/pit/config.toml`, e.g. `~/.config/pit/config.toml` on Linux.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(super) struct Config {
+    /// Directory of the cache, relative to the config file if not absolute. `~` is the home directory.
+    pub(super) cache_dir: Option<PathBuf>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|x| x.join("pit").join("config.toml"))
+}
+
+/// Reads the config file, if any. Called once at startup, so that a broken config is reported up front.
+pub(crate) fn load_config() -> Result<()> {
+    let config = match config_path() {
+        Some(path) if path.exists() => read_config(&path)?,
+        _ => Config::default(),
+    };
+    let _ = CONFIG.set(config);
+
+    Ok(())
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+    let content = fs::read_to_string(path)?;
+    let mut config: Config =
+        toml::from_str(&content).with_context(|| format!("Invalid config {}", path.display()))?;
+    if let Some(cache_dir) = &config.cache_dir {
+        let cache_dir = match (cache_dir.strip_prefix("~"), dirs::home_dir()) {
+            (Ok(rest), Some(home_dir)) => home_dir.join(rest),
+            _ => path.parent().unwrap().join(cache_dir),
+        };
+        config.cache_dir = Some(cache_dir);
+    }
+
+    Ok(config)
+}
+
+pub(super) fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[test]
+fn read_cache_dir_of_config() {
+    let dir = std::env::temp_dir().join(format!("rspit-test-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    let cache_dir_of = |content: &str| {
+        fs::write(&path, content).unwrap();
+        read_config(&path).map(|x| x.cache_dir)
+    };
+
+    assert_eq!(cache_dir_of("").unwrap(), None);
+    assert_eq!(
+        cache_dir_of("cache-dir = \"cache\"").unwrap(),
+        Some(dir.join("cache"))
+    );
+    assert_eq!(
+        cache_dir_of("cache-dir = \"/cache\"").unwrap(),
+        Some(PathBuf::from("/cache"))
+    );
+    if let Some(home_dir) = dirs::home_dir() {
+        assert_eq!(
+            cache_dir_of("cache-dir = \"~/cache\"").unwrap(),
+            Some(home_dir.join("cache"))
+        );
+    }
+    assert!(cache_dir_of("cache_dir = \"cache\"").is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, OnceLock},
+};
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{cache::Cache, package::Package, Profile};
+
+/// Environment variables read by cargo or rustc which change the artifacts.
+/// `CARGO_TARGET_DIR` is left out since pit passes `--target-dir`.
+const ENV_VARS: [&str; 12] = [
+    "RUSTFLAGS",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "RUSTC",
+    "RUSTC_WRAPPER",
+    "RUSTC_WORKSPACE_WRAPPER",
+    "RUSTUP_TOOLCHAIN",
+    "CC",
+    "CXX",
+    "CFLAGS",
+    "CXXFLAGS",
+    "AR",
+    "LDFLAGS",
+];
+const ENV_VAR_PREFIXES: [&str; 3] = ["CARGO_BUILD_", "CARGO_PROFILE_", "CARGO_TARGET_"];
+
+/// One input of the identity hash.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Component {
+    pub(super) name: String,
+    /// What was hashed, for humans.
+    pub(super) description: String,
+    pub(super) hash: String,
+}
+
+impl Component {
+    fn new(name: impl Into<String>, description: impl Into<String>, content: &[u8]) -> Component {
+        Component {
+            name: name.into(),
+            description: description.into(),
+            hash: format!("{:x}", Sha256::digest(content)),
+        }
+    }
+}
+
+/// Everything a build of a package depends on, deciding whether its cached artifacts can be reused.
+///
+/// The identity of the last successful build is kept in the cache,
+/// so that the inputs which changed since can be told.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Identity {
+    pub(super) components: Vec<Component>,
+}
+
+impl Identity {
+    /// `cargo_args` are the flags the package is built with.
+    pub(super) fn new(
+        package: &Package,
+        packages: &[Package],
+        cache: &Cache,
+        cargo_args: &[String],
+    ) -> Identity {
+        let mut components = vec![Component::new(
+            "manifest",
+            "Cargo.toml",
+            package.toml.as_bytes(),
+        )];
+        components.push(Component::new(
+            package.src_path(),
+            package.src_path(),
+            package.src.as_bytes(),
+        ));
+        for file in &package.files {
+            let path = file.path.to_string_lossy();
+            components.push(Component::new(path.clone(), path, file.content.as_bytes()));
+        }
+        for dependency in package.dependencies(packages) {
+            components.push(Component::new(
+                format!("dependency {}", dependency.name),
+                format!("sources of the {} package", dependency.name),
+                dependency.identity_hash(packages).as_bytes(),
+            ));
+        }
+
+        components.push(Component::new(
+            "cargo args",
+            if cargo_args.is_empty() {
+                "no flags".to_owned()
+            } else {
+                cargo_args.join(" ")
+            },
+            cargo_args.join("\n").as_bytes(),
+        ));
+
+        let toolchain = toolchain(&cache.project_dir);
+        components.push(Component::new(
+            "toolchain",
+            toolchain.lines().next().unwrap_or_default(),
+            toolchain.as_bytes(),
+        ));
+
+        let env_vars = env_vars();
+        let env_vars = env_vars
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<String>>();
+        components.push(Component::new(
+            "env",
+            if env_vars.is_empty() {
+                "no variables set".to_owned()
+            } else {
+                env_vars.join(" ")
+            },
+            env_vars.join("\n").as_bytes(),
+        ));
+
+        let mut config = Vec::new();
+        let config_files = cargo_config_files(&cache.project_dir);
+        for file in &config_files {
+            config.extend(file.to_string_lossy().as_bytes());
+            config.extend(fs::read(file).unwrap_or_default());
+        }
+        components.push(Component::new(
+            "cargo config",
+            if config_files.is_empty() {
+                "no files".to_owned()
+            } else {
+                config_files
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            },
+            &config,
+        ));
+
+        // Written by cargo on the first build, and kept in the project directory afterwards.
+        let lockfile = fs::read(&cache.cargo_lock);
+        components.push(Component::new(
+            "lockfile",
+            if lockfile.is_ok() {
+                "Cargo.lock"
+            } else {
+                "not resolved yet"
+            },
+            &lockfile.unwrap_or_default(),
+        ));
+
+        Identity { components }
+    }
+
+    /// Why the package is rebuilt, none if its cached artifacts are used.
+    pub(super) fn rebuild_reasons(&self, cache: &Cache, profile: Profile) -> Vec<String> {
+        if cache.is_same_identity_hash(&self.hash(), profile) {
+            return Vec::new();
+        }
+        if cache.dir.join("journal").exists() {
+            return vec!["the last build was interrupted".to_owned()];
+        }
+        if cache.identity_hash(profile).is_some() && cache.executables(profile).is_none() {
+            return vec!["the executables of the last build were not recorded".to_owned()];
+        }
+        let Some(last) = cache.last_identity(profile) else {
+            if cache.identity_hash(profile).is_some() {
+                return vec!["the inputs of the last build were not recorded".to_owned()];
+            }
+            return vec!["it has never been built".to_owned()];
+        };
+
+        let mut reasons = Vec::new();
+        for component in &self.components {
+            match last.components.iter().find(|x| x.name == component.name) {
+                None => reasons.push(format!("{} was added", component.name)),
+                Some(x) if x.hash == component.hash => {}
+                Some(x) if x.description == component.description => {
+                    reasons.push(format!("{} changed", component.name))
+                }
+                Some(x) => reasons.push(format!(
+                    "{} changed from {} to {}",
+                    component.name, x.description, component.description
+                )),
+            }
+        }
+        for component in &last.components {
+            if !self.components.iter().any(|x| x.name == component.name) {
+                reasons.push(format!("{} was removed", component.name));
+            }
+        }
+        if reasons.is_empty() {
+            reasons.push("the cached artifacts were discarded".to_owned());
+        }
+        reasons
+    }
+
+    pub(super) fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        for component in &self.components {
+            hasher.update(&component.name);
+            hasher.update(&component.hash);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Output of `rustc -vV`, with the version, commit and host of the toolchain cargo uses.
+///
+/// It is run in the project directory, or its closest existing ancestor,
+/// since rustup picks the toolchain from the `rust-toolchain.toml` of the directory cargo runs in.
+fn toolchain(project_dir: &Path) -> String {
+    static TOOLCHAINS: OnceLock<Mutex<HashMap<PathBuf, String>>> = OnceLock::new();
+
+    let dir = project_dir
+        .ancestors()
+        .find(|x| x.is_dir())
+        .unwrap_or(project_dir);
+    let mut toolchains = TOOLCHAINS.get_or_init(Default::default).lock().unwrap();
+    toolchains
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| {
+            let rustc = env::var_os("RUSTC").unwrap_or("rustc".into());
+            process::Command::new(rustc)
+                .arg("-vV")
+                .current_dir(dir)
+                .output()
+                .ok()
+                .filter(|x| x.status.success())
+                .map(|x| String::from_utf8_lossy(&x.stdout).into_owned())
+                .unwrap_or("unknown toolchain".to_owned())
+        })
+        .clone()
+}
+
+fn env_vars() -> Vec<(String, String)> {
+    let mut env_vars = env::vars()
+        .filter(|(name, _)| {
+            ENV_VARS.contains(&name.as_str())
+                || ENV_VAR_PREFIXES.iter().any(|x| name.starts_with(x))
+        })
+        .filter(|(name, _)| name != "CARGO_TARGET_DIR" && name != "CARGO_BUILD_TARGET_DIR")
+        .collect::<Vec<(String, String)>>();
+    env_vars.sort();
+    env_vars
+}
+
+/// The config files cargo reads when building in the directory:
+/// those in `.cargo` of the directory and its ancestors, and those in cargo home.
+fn cargo_config_files(dir: &Path) -> Vec<PathBuf> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|x| x.join(".cargo")));
+
+    let mut config_dirs = dir
+        .ancestors()
+        .map(|x| x.join(".cargo"))
+        .collect::<Vec<PathBuf>>();
+    config_dirs.extend(cargo_home);
+
+    let mut files = Vec::new();
+    for config_dir in config_dirs {
+        for name in ["config.toml", "config"] {
+            let file = config_dir.join(name);
+            if file.is_file() && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+#[test]
+fn rebuild_reasons_compare_with_the_last_build() {
+    let dir = env::temp_dir().join(format!("rspit-test-rebuild-reasons-{}", process::id()));
+    let cache = Cache::in_dir(dir.clone());
+    let identity = |components: &[(&str, &str)]| Identity {
+        components: components
+            .iter()
+            .map(|(name, description)| Component::new(*name, *description, description.as_bytes()))
+            .collect(),
+    };
+    let last = identity(&[
+        ("manifest", "a"),
+        ("toolchain", "1.0"),
+        ("cargo args", "-v"),
+    ]);
+
+    assert_eq!(
+        last.rebuild_reasons(&cache, Profile::Debug),
+        ["it has never been built"]
+    );
+
+    cache.write_identity(&last, Profile::Debug).unwrap();
+    assert_eq!(
+        last.rebuild_reasons(&cache, Profile::Debug),
+        ["the executables of the last build were not recorded"]
+    );
+
+    cache.write_executables(Vec::new(), Profile::Debug).unwrap();
+    assert!(last.rebuild_reasons(&cache, Profile::Debug).is_empty());
+
+    let current = identity(&[("manifest", "b"), ("toolchain", "1.1"), ("env", "X=1")]);
+    assert_eq!(
+        current.rebuild_reasons(&cache, Profile::Debug),
+        [
+            "manifest changed from a to b",
+            "toolchain changed from 1.0 to 1.1",
+            "env was added",
+            "cargo args was removed",
+        ]
+    );
+
+    fs::write(cache.dir.join("journal"), "build\n").unwrap();
+    assert_eq!(
+        last.rebuild_reasons(&cache, Profile::Debug),
+        ["the last build was interrupted"]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
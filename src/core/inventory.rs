@@ -0,0 +1,532 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use tiny_ansi::TinyAnsi;
+
+use super::{
+    cache::{self, Cache},
+    identity::Identity,
+    identity_cargo_args,
+    journal::discard_identity_hashes,
+    output::{emit, print_line, timestamp, Event, StateStats},
+    package::{parse_snippet, Level, Package},
+    utils::{dir_size, format_age, format_size},
+    BuildOptions, Profile,
+};
+
+/// What the snippet of a cache entry has become.
+enum Snippet {
+    Parsed(Vec<Package>),
+    /// The snippet has errors, so whether its packages still exist is unknown.
+    Broken,
+    /// The snippet was removed, or the entry was left by an older pit which did not record it.
+    Missing,
+}
+
+impl Snippet {
+    fn load(path: Option<&Path>) -> Snippet {
+        let Some(content) = path.and_then(|x| fs::read_to_string(x).ok()) else {
+            return Snippet::Missing;
+        };
+        let (packages, diagnostics) = parse_snippet(path.unwrap(), &content);
+        if diagnostics.iter().all(|x| x.level != Level::Error) {
+            Snippet::Parsed(packages)
+        } else {
+            Snippet::Broken
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    UpToDate,
+    Outdated,
+    NotBuilt,
+    Interrupted,
+    Unknown,
+    Orphaned,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            State::UpToDate => "up to date",
+            State::Outdated => "outdated",
+            State::NotBuilt => "not built",
+            State::Interrupted => "interrupted",
+            State::Unknown => "unknown",
+            State::Orphaned => "orphaned",
+        };
+        f.write_str(state)
+    }
+}
+
+const STATES: [State; 6] = [
+    State::UpToDate,
+    State::Outdated,
+    State::NotBuilt,
+    State::Interrupted,
+    State::Unknown,
+    State::Orphaned,
+];
+
+/// A cache entry, either of a package or of the workspace of a snippet.
+struct Entry {
+    dir_name: String,
+    name: String,
+    cache: Cache,
+    snippet_path: Option<PathBuf>,
+    size: u64,
+    last_used: Option<SystemTime>,
+    state: State,
+    problems: Vec<String>,
+}
+
+impl Entry {
+    fn is_workspace(&self) -> bool {
+        self.name == ".workspace"
+    }
+
+    fn snippet_display(&self) -> String {
+        match &self.snippet_path {
+            Some(path) => path.display().to_string(),
+            None => format!("unknown snippet {}", self.dir_name),
+        }
+    }
+
+    fn description(&self) -> String {
+        if self.is_workspace() {
+            format!("workspace cache of {}", self.snippet_display())
+        } else {
+            format!("{} cache of {}", self.name, self.snippet_display())
+        }
+    }
+}
+
+/// Every cache entry, the least recently used first.
+fn entries() -> Result<Vec<Entry>> {
+    let mut snippets = HashMap::new();
+    let mut entries = Vec::new();
+
+    for (dir_name, name) in cache::entries()? {
+        let snippet_path = cache::snippet_path(&dir_name);
+        let snippet = snippets
+            .entry(dir_name.clone())
+            .or_insert_with(|| Snippet::load(snippet_path.as_deref()));
+
+        let cache = Cache::in_snippet_dir(&dir_name, &name);
+        let member_hashes = if name == ".workspace" {
+            workspace_identity_hashes(&cache.dir)
+        } else {
+            cache
+                .identity_hash(Profile::Debug)
+                .into_iter()
+                .map(|x| (name.clone(), x))
+                .collect()
+        };
+        let state = state(&dir_name, &name, &cache, snippet);
+        let problems = problems(&dir_name, &name, &cache, &member_hashes, snippet);
+
+        entries.push(Entry {
+            size: dir_size(&cache.dir),
+            last_used: cache.last_used(),
+            dir_name,
+            name,
+            cache,
+            snippet_path,
+            state,
+            problems,
+        });
+    }
+    entries.sort_by_key(|x| x.last_used);
+
+    Ok(entries)
+}
+
+fn workspace_identity_hashes(dir: &Path) -> Vec<(String, String)> {
+    let Ok(files) = dir.join("identity_hash").read_dir() else {
+        return Vec::new();
+    };
+    files
+        .flatten()
+        .filter_map(|x| {
+            let hash = fs::read_to_string(x.path()).ok()?;
+            Some((x.file_name().to_string_lossy().into_owned(), hash))
+        })
+        .collect()
+}
+
+fn state(dir_name: &str, name: &str, cache: &Cache, snippet: &Snippet) -> State {
+    if cache.dir.join("journal").exists() {
+        return State::Interrupted;
+    }
+    let packages = match snippet {
+        Snippet::Parsed(packages) => packages,
+        Snippet::Broken => return State::Unknown,
+        Snippet::Missing => return State::Orphaned,
+    };
+
+    let (members, caches) = if name == ".workspace" {
+        let caches = packages
+            .iter()
+            .map(|x| Cache::in_snippet_workspace(dir_name, &x.name))
+            .collect::<Vec<Cache>>();
+        (packages.iter().collect::<Vec<&Package>>(), caches)
+    } else {
+        let Some(package) = packages.iter().find(|x| x.name == name) else {
+            return State::Orphaned;
+        };
+        (vec![package], vec![Cache::in_snippet_dir(dir_name, name)])
+    };
+
+    let up_to_date = members
+        .iter()
+        .zip(&caches)
+        .filter(|(package, cache)| {
+            cache.is_same_identity_hash(
+                &Identity::new(
+                    package,
+                    packages,
+                    cache,
+                    &identity_cargo_args(package, &BuildOptions::default()).unwrap_or_default(),
+                )
+                .hash(),
+                Profile::Debug,
+            )
+        })
+        .count();
+    if up_to_date == members.len() {
+        State::UpToDate
+    } else if caches
+        .iter()
+        .all(|x| x.identity_hash(Profile::Debug).is_none())
+    {
+        State::NotBuilt
+    } else {
+        State::Outdated
+    }
+}
+
+/// Inconsistencies left by an interrupted or failed transaction, or by damage to the cache directory.
+fn problems(
+    dir_name: &str,
+    name: &str,
+    cache: &Cache,
+    member_hashes: &[(String, String)],
+    snippet: &Snippet,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Ok(journal) = fs::read_to_string(cache.dir.join("journal")) {
+        problems.push(format!(
+            "unfinished {}",
+            journal.lines().next().unwrap_or_default()
+        ));
+    }
+    if member_hashes.is_empty() {
+        return problems;
+    }
+
+    for (member, hash) in member_hashes {
+        if hash.len() != 64 || !hash.chars().all(|x| x.is_ascii_hexdigit()) {
+            problems.push(format!("corrupted identity hash of {member}"));
+        }
+    }
+    if !cache.project_dir.is_dir() {
+        problems.push("identity hash without a project directory".to_owned());
+    }
+    if !cache.target_dir.is_dir() {
+        problems.push("identity hash without a target directory".to_owned());
+    }
+
+    if let Snippet::Parsed(packages) = snippet {
+        for (member, _) in member_hashes {
+            let Some(package) = packages.iter().find(|x| &x.name == member) else {
+                continue;
+            };
+            let member_cache = if name == ".workspace" {
+                Cache::in_snippet_workspace(dir_name, member)
+            } else {
+                Cache::in_snippet_dir(dir_name, member)
+            };
+            let executables = member_cache.executables(Profile::Debug).unwrap_or_default();
+            if !package.lib && executables.iter().any(|x| !x.path.exists()) {
+                problems.push(format!("missing executable of {member}"));
+            }
+        }
+    }
+
+    problems
+}
+
+// Ls
+
+pub(crate) fn list_cache() -> Result<()> {
+    let mut entries = entries()?;
+    entries.sort_by(|a, b| {
+        (&a.snippet_path, &a.dir_name, &a.name).cmp(&(&b.snippet_path, &b.dir_name, &b.name))
+    });
+
+    let width = entries
+        .iter()
+        .map(|x| x.name.len())
+        .max()
+        .unwrap_or_default();
+    let mut snippet = None;
+    for entry in &entries {
+        if snippet != Some(&entry.dir_name) {
+            snippet = Some(&entry.dir_name);
+            print_line(entry.snippet_display().bold());
+        }
+
+        let last_used = entry.last_used.map(format_age).unwrap_or_default();
+        print_line(format!(
+            "    {:<width$}  {:>10}  {:<14}  {}",
+            entry.name,
+            format_size(entry.size),
+            last_used,
+            entry.state
+        ));
+        emit(Event::CacheEntry {
+            snippet: entry.snippet_path.clone(),
+            name: entry.name.clone(),
+            size: entry.size,
+            last_used: entry.last_used.and_then(timestamp),
+            state: entry.state.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Stats
+
+pub(crate) fn show_cache_stats() -> Result<()> {
+    let entries = entries()?;
+
+    let snippets = entries
+        .iter()
+        .map(|x| &x.dir_name)
+        .collect::<HashSet<&String>>()
+        .len();
+    let total_size = entries.iter().map(|x| x.size).sum::<u64>();
+    let workspace_entries = entries.iter().filter(|x| x.is_workspace()).count();
+    let least_recently_used = entries.first().and_then(|x| x.last_used);
+
+    print_line(format!("Cache directory: {}", cache::root_dir().display()));
+    print_line(format!("Snippets: {snippets}"));
+    print_line(format!("Entries: {}", entries.len()));
+    print_line(format!("Workspace entries: {workspace_entries}"));
+    print_line(format!("Total size: {}", format_size(total_size)));
+    let mut states = BTreeMap::new();
+    for state in STATES {
+        let matching = entries
+            .iter()
+            .filter(|x| x.state == state)
+            .collect::<Vec<&Entry>>();
+        if !matching.is_empty() {
+            let size = matching.iter().map(|x| x.size).sum();
+            print_line(format!(
+                "    {state}: {} ({})",
+                matching.len(),
+                format_size(size)
+            ));
+            states.insert(
+                state.to_string(),
+                StateStats {
+                    entries: matching.len(),
+                    size,
+                },
+            );
+        }
+    }
+    if let Some(oldest) = least_recently_used {
+        print_line(format!("Least recently used: {}", format_age(oldest)));
+    }
+    emit(Event::CacheStats {
+        cache_dir: cache::root_dir(),
+        snippets,
+        entries: entries.len(),
+        workspace_entries,
+        total_size,
+        states,
+        least_recently_used: least_recently_used.and_then(timestamp),
+    });
+
+    Ok(())
+}
+
+// Prune
+
+/// Removes the entries unused for longer than `older_than`, the orphaned entries if `orphans`,
+/// then the least recently used entries until the cache fits in `max_size`.
+pub(crate) fn prune_cache(
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+    orphans: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if older_than.is_none() && max_size.is_none() && !orphans {
+        bail!("Nothing to prune, specify --older-than, --max-size or --orphans.");
+    }
+
+    let entries = entries()?;
+    let now = SystemTime::now();
+    let mut pruned = entries
+        .iter()
+        .map(|entry| {
+            let is_old = older_than.is_some_and(|older_than| {
+                entry
+                    .last_used
+                    .is_none_or(|x| now.duration_since(x).unwrap_or_default() > older_than)
+            });
+            is_old || (orphans && entry.state == State::Orphaned)
+        })
+        .collect::<Vec<bool>>();
+
+    if let Some(max_size) = max_size {
+        let mut size = entries
+            .iter()
+            .zip(&pruned)
+            .filter(|(_, pruned)| !**pruned)
+            .map(|(x, _)| x.size)
+            .sum::<u64>();
+        for (entry, pruned) in entries.iter().zip(pruned.iter_mut()) {
+            if size <= max_size {
+                break;
+            }
+            if !*pruned {
+                *pruned = true;
+                size -= entry.size;
+            }
+        }
+    }
+
+    let mut freed = 0;
+    for (entry, _) in entries.iter().zip(&pruned).filter(|(_, x)| **x) {
+        if remove_entry(entry, dry_run)? {
+            freed += entry.size;
+        }
+    }
+
+    let output_text = if dry_run {
+        format!("Would free {}", format_size(freed))
+    } else {
+        format!("Freed {}", format_size(freed))
+    }
+    .bright_green()
+    .bold();
+    print_line(output_text);
+    emit(Event::Freed {
+        size: freed,
+        dry_run,
+    });
+
+    Ok(())
+}
+
+/// Returns whether the entry was removed, entries in use by another process being skipped.
+fn remove_entry(entry: &Entry, dry_run: bool) -> Result<bool> {
+    let description = entry.description();
+    let size = format_size(entry.size);
+
+    let removed = Event::Removed {
+        description: description.clone(),
+        size: entry.size,
+        dry_run,
+    };
+
+    if dry_run {
+        print_line(format!(
+            "Would remove the {description} ({size}, {})",
+            entry.state
+        ));
+        emit(removed);
+        return Ok(true);
+    }
+
+    let Some(_lock) = entry.cache.try_lock()? else {
+        let output_text = format!("Skip removing the {description} because it is in use.")
+            .bright_yellow()
+            .bold();
+        print_line(output_text);
+        return Ok(false);
+    };
+    print_line(format!(
+        "Remove the {description} ({size}, {})",
+        entry.state
+    ));
+    fs::remove_dir_all(&entry.cache.dir)?;
+    cache::remove_snippet_dir_if_empty(&entry.dir_name)?;
+    emit(removed);
+
+    Ok(true)
+}
+
+// Verify
+
+/// Reports the entries left inconsistent, and with `fix`, discards their identity hashes,
+/// so that their packages are rebuilt next time.
+pub(crate) fn verify_cache(fix: bool) -> Result<()> {
+    let entries = entries()?;
+
+    let mut damaged = 0;
+    for entry in entries.iter().filter(|x| !x.problems.is_empty()) {
+        let Some(_lock) = entry.cache.try_lock()? else {
+            let output_text = format!(
+                "Skip verifying the {} because it is in use.",
+                entry.description()
+            )
+            .bright_yellow()
+            .bold();
+            print_line(output_text);
+            continue;
+        };
+        damaged += 1;
+
+        let output_text = format!(
+            "Damaged {}: {}",
+            entry.description(),
+            entry.problems.join(", ")
+        )
+        .bright_yellow()
+        .bold();
+        print_line(output_text);
+
+        if fix {
+            discard_identity_hashes(&entry.cache.dir)?;
+            let journal = entry.cache.dir.join("journal");
+            if journal.exists() {
+                fs::remove_file(journal)?;
+            }
+
+            let output_text = format!("Repaired the {}", entry.description())
+                .bright_green()
+                .bold();
+            print_line(output_text);
+        }
+        emit(Event::Damaged {
+            description: entry.description(),
+            problems: entry.problems.clone(),
+            repaired: fix,
+        });
+    }
+
+    if damaged == 0 {
+        let output_text = format!("All {} cache entries are intact.", entries.len())
+            .bright_green()
+            .bold();
+        print_line(output_text);
+    } else if !fix {
+        bail!(
+            "Found {damaged} damaged cache entries, run `pit cache verify --fix` to repair them."
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,283 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use tiny_ansi::TinyAnsi;
+
+use super::{
+    cache::{identity_hash_name, restore_target},
+    output::print_line,
+    progress::Progress,
+    Profile,
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Lets the current cache transaction finish on the first Ctrl-C, so that the cache is left consistent.
+/// Cargo and the executed package receive the signal too and stop by themselves.
+/// A second Ctrl-C exits immediately, the next run recovers the cache.
+pub(crate) fn install_signal_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+    })?;
+
+    Ok(())
+}
+
+pub(crate) fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub(super) fn bail_if_interrupted() -> Result<()> {
+    if is_interrupted() {
+        bail!("Interrupted.");
+    }
+
+    Ok(())
+}
+
+/// An operation in progress on a cache entry.
+///
+/// The journal file is written before the entry is modified and removed once it is done.
+/// If it is still there when the next transaction begins, the process was killed in between,
+/// and the identity hashes of the entry are discarded since they may not match its artifacts anymore.
+pub(super) struct Transaction {
+    journal: PathBuf,
+}
+
+impl Transaction {
+    /// The recovery of an interrupted transaction is told through the progress display of a parallel command, if any.
+    pub(super) fn begin<P: AsRef<Path>>(
+        cache_dir: P,
+        operation: &str,
+        progress: Option<&Progress>,
+    ) -> Result<Transaction> {
+        let cache_dir = cache_dir.as_ref();
+        let journal = cache_dir.join("journal");
+
+        if let Ok(interrupted) = fs::read_to_string(&journal) {
+            let output_text = format!(
+                "Recover the cache in {} after an interrupted {}.",
+                cache_dir.display(),
+                interrupted.lines().next().unwrap_or_default()
+            )
+            .bright_yellow()
+            .bold();
+            match progress {
+                Some(progress) => progress.print(output_text),
+                None => print_line(output_text),
+            }
+
+            discard_identity_hashes(cache_dir)?;
+        }
+
+        fs::create_dir_all(cache_dir)?;
+        fs::write(&journal, format!("{operation}\n{}\n", process::id()))?;
+
+        Ok(Transaction { journal })
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // Failed transactions end here too, having already discarded what they invalidated.
+        let _ = fs::remove_file(&self.journal);
+    }
+}
+
+/// Discards the identity hashes of every profile in the cache entry.
+pub(super) fn discard_identity_hashes(cache_dir: &Path) -> Result<()> {
+    for profile in [Profile::Debug, Profile::Release] {
+        discard_identity_hash(cache_dir, profile)?;
+    }
+
+    Ok(())
+}
+
+pub(super) fn discard_identity_hash(cache_dir: &Path, profile: Profile) -> Result<()> {
+    let identity_hash = cache_dir.join(identity_hash_name(profile));
+    if identity_hash.is_dir() {
+        fs::remove_dir_all(&identity_hash)?;
+    } else if identity_hash.exists() {
+        fs::remove_file(&identity_hash)?;
+    }
+
+    Ok(())
+}
+
+/// Temporary directories unchanged for this long are no longer used by the pit which created them.
+const ORPHAN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Recovers the temporary directories left by versions of pit which built in `pit-<random>` directories,
+/// and were killed before moving the target directory back to the cache.
+///
+/// The target directories are moved back to their cache entries when these can be told apart,
+/// then the directories are removed. Only directories of the current user unchanged for a day are touched,
+/// and failures are reported without stopping the command.
+pub(crate) fn recover_orphaned_temp_dirs() {
+    recover_orphaned_dirs_in(&env::temp_dir());
+}
+
+fn recover_orphaned_dirs_in(temp_dir: &Path) {
+    let Ok(entries) = temp_dir.read_dir() else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        if !is_orphaned_temp_dir(&dir) {
+            continue;
+        }
+
+        if let Err(e) = recover_temp_dir(&dir) {
+            let output_text = format!(
+                "Failed to recover the orphaned temporary directory {}: {e:#}",
+                dir.display()
+            )
+            .bright_yellow()
+            .bold();
+            eprintln!("{output_text}");
+        }
+    }
+}
+
+fn is_orphaned_temp_dir(dir: &Path) -> bool {
+    let Some(suffix) = dir
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.strip_prefix("pit-"))
+    else {
+        return false;
+    };
+    if suffix.len() != 16 || !suffix.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return false;
+    }
+
+    let Ok(metadata) = fs::symlink_metadata(dir) else {
+        return false;
+    };
+    metadata.is_dir()
+        && is_abandoned(
+            is_owned_by_current_user(&metadata),
+            last_modified(dir, 4),
+            SystemTime::now(),
+        )
+}
+
+/// Whether a temporary directory was left by a pit which is no longer running.
+/// Directories of other users are never touched, as the temporary directory may be shared.
+fn is_abandoned(is_owned: bool, last_modified: Option<SystemTime>, now: SystemTime) -> bool {
+    is_owned
+        && last_modified
+            .and_then(|x| now.duration_since(x).ok())
+            .is_some_and(|x| x >= ORPHAN_AGE)
+}
+
+#[cfg(unix)]
+fn is_owned_by_current_user(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: `geteuid` has no preconditions and cannot fail.
+    metadata.uid() == unsafe { libc::geteuid() }
+}
+
+#[cfg(not(unix))]
+fn is_owned_by_current_user(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+/// Latest modification of the directory and of its subdirectories down to `depth`,
+/// which cargo updates as long as it builds in the directory.
+fn last_modified(dir: &Path, depth: usize) -> Option<SystemTime> {
+    let mut latest = fs::metadata(dir).and_then(|x| x.modified()).ok()?;
+    if depth > 0 {
+        for entry in dir.read_dir().ok()?.flatten() {
+            if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                latest = latest.max(last_modified(&entry.path(), depth - 1)?);
+            }
+        }
+    }
+
+    Some(latest)
+}
+
+fn recover_temp_dir(temp_dir: &Path) -> Result<()> {
+    for entry in temp_dir.read_dir()? {
+        let package_dir = entry?.path();
+        let target_dir = package_dir.join("target");
+        let Some(package_name) = package_dir.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        if target_dir.is_dir() && restore_target(package_name, &target_dir)? {
+            let output_text = format!(
+                "Move the target directory of {package_name} left in {} back to the cache",
+                temp_dir.display()
+            )
+            .bright_yellow()
+            .bold();
+            eprintln!("{output_text}");
+        }
+    }
+
+    let output_text = format!(
+        "Remove the orphaned temporary directory {}",
+        temp_dir.display()
+    )
+    .bright_yellow()
+    .bold();
+    eprintln!("{output_text}");
+    fs::remove_dir_all(temp_dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn only_abandoned_temp_dirs_are_orphaned() {
+    let now = SystemTime::now();
+    let day_ago = now - ORPHAN_AGE;
+
+    assert!(is_abandoned(true, Some(day_ago), now));
+    // Another user's, in a shared temporary directory.
+    assert!(!is_abandoned(false, Some(day_ago), now));
+    // Still used by a running pit.
+    assert!(!is_abandoned(
+        true,
+        Some(now - Duration::from_secs(60)),
+        now
+    ));
+    assert!(!is_abandoned(true, None, now));
+}
+
+#[test]
+fn recent_temp_dirs_are_left_alone() {
+    let temp_dir = env::temp_dir().join(format!("rspit-test-orphans-{}", process::id()));
+    let recent = temp_dir.join("pit-0123456789abcdef");
+    let old = temp_dir.join("pit-fedcba9876543210");
+    let unrelated = temp_dir.join("pit-unrelated");
+    for dir in [&recent, &old, &unrelated] {
+        fs::create_dir_all(dir.join("app")).unwrap();
+    }
+    let day_ago = SystemTime::now() - ORPHAN_AGE;
+    for dir in [
+        old.join("app"),
+        old.clone(),
+        unrelated.join("app"),
+        unrelated.clone(),
+    ] {
+        fs::File::open(dir).unwrap().set_modified(day_ago).unwrap();
+    }
+
+    recover_orphaned_dirs_in(&temp_dir);
+    assert!(recent.exists());
+    assert!(!old.exists());
+    assert!(unrelated.exists());
+
+    fs::remove_dir_all(temp_dir).unwrap();
+}
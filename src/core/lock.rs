@@ -0,0 +1,85 @@
+use std::{
+    env,
+    fs::{self, File, TryLockError},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use tiny_ansi::TinyAnsi;
+
+use super::{journal::bail_if_interrupted, output::print_line, progress::Progress};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 600;
+
+/// Seconds to wait for a lock, from `PIT_LOCK_TIMEOUT`.
+fn timeout() -> Duration {
+    let secs = env::var("PIT_LOCK_TIMEOUT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// An advisory lock on a cache entry, released when it and its clones are dropped.
+///
+/// Other pit processes, and other threads of this one, wait for it before using the entry.
+#[derive(Clone)]
+pub(super) struct Lock {
+    _file: Arc<File>,
+}
+
+impl Lock {
+    /// Takes the lock, or returns `None` if it is held by someone else.
+    pub(super) fn try_acquire<P: AsRef<Path>>(lock_file: P) -> Result<Option<Lock>> {
+        let lock_file = lock_file.as_ref();
+        fs::create_dir_all(lock_file.parent().unwrap())?;
+        let file = File::create(lock_file)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Lock {
+                _file: Arc::new(file),
+            })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Takes the lock, waiting until it is released, the timeout elapses or pit is interrupted.
+    /// The message telling it waits goes through the progress display of a parallel command, if any.
+    pub(super) fn acquire<P: AsRef<Path>>(
+        lock_file: P,
+        description: &str,
+        progress: Option<&Progress>,
+    ) -> Result<Lock> {
+        if let Some(lock) = Lock::try_acquire(&lock_file)? {
+            return Ok(lock);
+        }
+
+        let output_text = format!("Waiting for lock on {description}")
+            .bright_yellow()
+            .bold();
+        match progress {
+            Some(progress) => progress.print(output_text),
+            None => print_line(output_text),
+        }
+
+        let timeout = timeout();
+        let start = Instant::now();
+        loop {
+            thread::sleep(Duration::from_millis(100));
+            bail_if_interrupted()?;
+            if let Some(lock) = Lock::try_acquire(&lock_file)? {
+                return Ok(lock);
+            }
+            if start.elapsed() >= timeout {
+                bail!(
+                    "Timed out after {} seconds waiting for lock on {description}.",
+                    timeout.as_secs()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+
+use super::package::Package;
+
+/// Settings of a package for pit, in `[package.metadata.pit]` of its manifest.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(super) struct Metadata {
+    /// Flags passed to cargo when checking or building the package.
+    #[serde(default)]
+    pub(super) cargo_args: Vec<String>,
+    #[serde(default)]
+    pub(super) run: RunMetadata,
+}
+
+/// How `run` executes the package, in `[package.metadata.pit.run]`.
+/// Paths are relative to the directory of the snippet.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(super) struct RunMetadata {
+    /// Replaced by the arguments given after `--`.
+    #[serde(default)]
+    pub(super) args: Vec<String>,
+    /// Set after the variables of `env-file`.
+    #[serde(default)]
+    pub(super) env: BTreeMap<String, String>,
+    /// `KEY=VALUE` lines, blank lines and `#` comments being skipped.
+    pub(super) env_file: Option<PathBuf>,
+    /// File read as the standard input.
+    pub(super) stdin: Option<PathBuf>,
+    pub(super) cwd: Option<PathBuf>,
+}
+
+impl Metadata {
+    pub(super) fn of(package: &Package) -> Result<Metadata> {
+        let value = package.toml.parse::<toml::Value>()?;
+        let Some(metadata) = value
+            .get("package")
+            .and_then(|x| x.get("metadata"))
+            .and_then(|x| x.get("pit"))
+        else {
+            return Ok(Metadata::default());
+        };
+
+        metadata.clone().try_into().with_context(|| {
+            format!(
+                "Invalid [package.metadata.pit] in the {} package",
+                package.name
+            )
+        })
+    }
+}
+
+/// Reads the variables of an env file.
+pub(super) fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the env file {}", path.display()))?;
+    parse_env(&content).with_context(|| format!("Invalid env file {}", path.display()))
+}
+
+/// Parses `KEY=VALUE` lines, optionally prefixed with `export` and with the value quoted.
+fn parse_env(content: &str) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            bail!("expected `KEY=VALUE` at line {}", index + 1);
+        };
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|x| value.strip_prefix(*x)?.strip_suffix(*x))
+            .unwrap_or(value);
+        env.push((name.trim().to_owned(), value.to_owned()));
+    }
+
+    Ok(env)
+}
+
+#[test]
+fn metadata_of_package() {
+    const INPUT: &str = r#"//# [package]
+//# name = "test"
+//#
+//# [package.metadata.pit.run]
+//# args = ["--verbose"]
+//# env = { LEVEL = "debug" }
+//# stdin = "input.txt"
+
+fn main() {}
+"#;
+    let package = Package::parse(INPUT, 0).unwrap();
+    let metadata = Metadata::of(&package).unwrap();
+    assert_eq!(metadata.run.args, ["--verbose"]);
+    assert_eq!(metadata.run.env["LEVEL"], "debug");
+    assert_eq!(metadata.run.stdin, Some(PathBuf::from("input.txt")));
+    assert_eq!(metadata.run.cwd, None);
+
+    let package = Package::parse(&INPUT.replace("stdin", "stdn"), 0).unwrap();
+    assert!(Metadata::of(&package).is_err());
+}
+
+#[test]
+fn parse_env_lines() {
+    let env = parse_env("# comment\n\nA=1\nexport B = \"two words\"\nC='x=y'\n").unwrap();
+    assert_eq!(
+        env,
+        [
+            ("A".to_owned(), "1".to_owned()),
+            ("B".to_owned(), "two words".to_owned()),
+            ("C".to_owned(), "x=y".to_owned()),
+        ]
+    );
+    assert!(parse_env("A").is_err());
+}
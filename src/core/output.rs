@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::{self, Write},
+    path::PathBuf,
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use serde_derive::Serialize;
+
+use super::summary::Record;
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// How the commands report what they do.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    /// Colored messages for humans.
+    Text,
+    /// One JSON event per line on stdout, the messages for humans going to stderr.
+    Json,
+}
+
+/// Chooses the format once at startup, before any output.
+pub(crate) fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+pub(super) fn is_json() -> bool {
+    FORMAT.get() == Some(&Format::Json)
+}
+
+/// Prints a message for humans, on stderr when stdout carries JSON events.
+pub(super) fn print_line(text: impl Display) {
+    if is_json() {
+        eprintln!("{text}");
+    } else {
+        println!("{text}");
+    }
+}
+
+/// Prints the event as a line of JSON, only in the JSON format.
+pub(crate) fn emit(event: Event) {
+    if !is_json() {
+        return;
+    }
+    let line = serde_json::to_string(&event).expect("Events are serializable.");
+    let _ = writeln!(io::stdout().lock(), "{line}");
+}
+
+/// Seconds since the Unix epoch of a time in an event.
+pub(super) fn timestamp(time: SystemTime) -> Option<u64> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|x| x.as_secs())
+}
+
+/// What `--format json` prints, tagged with its kind in the `event` field.
+/// Durations are in seconds and sizes in bytes.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A package of the snippet, as listed by `list` and `list-caches`.
+    Package {
+        name: String,
+        lib: bool,
+        /// Lines of the package section, 1-based and inclusive.
+        start_line: usize,
+        end_line: usize,
+        /// Line where the source of the package begins.
+        src_line: usize,
+        /// Names of the packages of the snippet it depends on.
+        dependencies: Vec<String>,
+    },
+    /// A problem in the snippet file, the package sections with errors being skipped.
+    Diagnostic {
+        level: &'static str,
+        file: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    CheckStarted {
+        package: String,
+    },
+    CheckFinished {
+        package: String,
+        success: bool,
+    },
+    BuildStarted {
+        package: String,
+        profile: &'static str,
+    },
+    BuildFinished {
+        package: String,
+        profile: &'static str,
+        /// Whether the build was skipped because nothing changed since the last one.
+        cached: bool,
+        success: bool,
+        duration: Option<f64>,
+        executables: Vec<PathBuf>,
+    },
+    RunStarted {
+        package: String,
+        executable: PathBuf,
+    },
+    RunFinished {
+        package: String,
+        /// 128 + the signal for a killed process.
+        exit_code: i32,
+        signal: Option<i32>,
+        duration: f64,
+    },
+    /// An executable copied by `release`.
+    ArtifactCopied {
+        package: String,
+        path: PathBuf,
+    },
+    PackageFailed {
+        package: String,
+        message: String,
+    },
+    /// What happened to each package, at the end of `build`, `run` and `release`.
+    Summary {
+        packages: Vec<Record>,
+    },
+    Identity {
+        package: String,
+        hash: String,
+        state: &'static str,
+        components: Vec<IdentityComponent>,
+    },
+    /// Why the package would be rebuilt, no reason meaning it is cached.
+    Explanation {
+        package: String,
+        reasons: Vec<String>,
+    },
+    /// A snippet created by `init`.
+    Created {
+        path: PathBuf,
+    },
+    /// A package added by `add`.
+    Added {
+        package: String,
+    },
+    Extracted {
+        package: String,
+        path: PathBuf,
+    },
+    CacheEntry {
+        snippet: Option<PathBuf>,
+        name: String,
+        size: u64,
+        last_used: Option<u64>,
+        state: String,
+    },
+    CacheStats {
+        cache_dir: PathBuf,
+        snippets: usize,
+        entries: usize,
+        workspace_entries: usize,
+        total_size: u64,
+        states: BTreeMap<String, StateStats>,
+        least_recently_used: Option<u64>,
+    },
+    /// A cache entry or directory removed by `clean` or `cache prune`, or which would be with `--dry-run`.
+    Removed {
+        description: String,
+        size: u64,
+        dry_run: bool,
+    },
+    Freed {
+        size: u64,
+        dry_run: bool,
+    },
+    Damaged {
+        description: String,
+        problems: Vec<String>,
+        repaired: bool,
+    },
+    /// The error pit exits with.
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+pub(crate) struct IdentityComponent {
+    pub(super) name: String,
+    pub(super) hash: String,
+    pub(super) description: String,
+}
+
+/// The entries of the cache in one state.
+#[derive(Serialize)]
+pub(crate) struct StateStats {
+    pub(super) entries: usize,
+    pub(super) size: u64,
+}
+
+#[test]
+fn events_are_tagged() {
+    let event = Event::RunFinished {
+        package: "app".to_owned(),
+        exit_code: 134,
+        signal: Some(6),
+        duration: 0.5,
+    };
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"event":"run_finished","package":"app","exit_code":134,"signal":6,"duration":0.5}"#
+    );
+
+    let event = Event::CheckStarted {
+        package: "app".to_owned(),
+    };
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"event":"check_started","package":"app"}"#
+    );
+}
@@ -1,60 +1,713 @@
-use std::{fs, path::Path};
+use std::{
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+};
 
+use anyhow::{bail, Context, Result};
 use sha2::{Digest, Sha256};
+use tiny_ansi::TinyAnsi;
+
+use super::output::{emit, Event};
 
 #[derive(Debug)]
 pub(crate) struct Package {
     pub(crate) name: String,
     pub(crate) toml: String,
     pub(crate) src: String,
+    /// Additional files started by `//# @file <path>` markers.
+    pub(crate) files: Vec<SourceFile>,
+    /// Whether the package has a `[lib]` target, in which case `src` is `src/lib.rs`.
+    pub(crate) lib: bool,
+    /// Dependencies on other packages of the same snippet.
+    pub(crate) local_dependencies: Vec<LocalDependency>,
+
+    /// Lines of the package section in the snippet file, 1-based and inclusive.
+    pub(crate) start_line: usize,
+    pub(crate) end_line: usize,
+    /// Line of the snippet file where `src` begins.
+    pub(crate) src_line: usize,
 }
 
-impl From<&str> for Package {
-    fn from(src: &str) -> Self {
-        let toml = src
-            .lines()
-            .skip_while(|line| line.is_empty())
-            .take_while(|line| line.starts_with("//#"))
-            .map(|line| line[3..].trim())
-            .collect::<Vec<&str>>()
-            .join("\n");
+#[derive(Debug)]
+pub(crate) struct SourceFile {
+    /// Path relative to the package directory.
+    pub(crate) path: PathBuf,
+    pub(crate) content: String,
+}
+
+/// A dependency declared as `name = { pit = true }` or `name = { path = "@name" }`.
+#[derive(Debug)]
+pub(crate) struct LocalDependency {
+    pub(crate) name: String,
+    /// Location of the dependency in the snippet file.
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+/// An error in one package section, located relative to the snippet file.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Level {
+    Error,
+    Warning,
+}
+
+/// A problem in one package section of the snippet file.
+#[derive(Debug)]
+pub(crate) struct Diagnostic {
+    pub(crate) level: Level,
+    pub(crate) file_path: PathBuf,
+    pub(crate) section: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) message: String,
+}
+
+impl Diagnostic {
+    fn new(level: Level, file_path: &Path, section: usize, error: ParseError) -> Diagnostic {
+        Diagnostic {
+            level,
+            file_path: file_path.to_owned(),
+            section,
+            line: error.line,
+            column: error.column,
+            message: error.message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {} (in package section {})",
+            self.file_path.display(),
+            self.line,
+            self.column,
+            self.message,
+            self.section
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
 
-        let src = src
-            .lines()
-            .skip_while(|line| line.is_empty() || line.starts_with("//#"))
+impl TryFrom<&str> for Package {
+    type Error = ParseError;
+
+    fn try_from(src: &str) -> Result<Self, Self::Error> {
+        Package::parse(src, 0)
+    }
+}
+
+impl Package {
+    /// Parses one package section.
+    /// `line_offset` is the number of snippet lines preceding the section,
+    /// so that errors point at the line in the snippet file.
+    pub(crate) fn parse(section: &str, line_offset: usize) -> Result<Package, ParseError> {
+        let lines = section.lines().collect::<Vec<&str>>();
+
+        // Each manifest line is kept with its (line, column) in the snippet,
+        // to map toml errors back to the `//#` line they come from.
+        let mut locations = Vec::new();
+        let toml = lines
+            .iter()
+            .enumerate()
+            .skip_while(|(_, line)| line.is_empty())
+            .take_while(|(_, line)| line.starts_with("//#") && file_marker(line).is_none())
+            .map(|(index, line)| {
+                let content = line[3..].trim();
+                let column = line.len() - line[3..].trim_start().len();
+                locations.push((line_offset + index + 1, column + 1));
+                content
+            })
             .collect::<Vec<&str>>()
             .join("\n");
 
-        let name = {
-            let value = toml
-                .parse::<toml::Value>()
-                .expect("Failed to parse string into toml.");
-            value["package"]["name"]
-                .as_str()
-                .expect("Failed to extract name from toml.")
-                .to_owned()
+        let start_line = line_offset + 1 + lines.iter().take_while(|x| x.is_empty()).count();
+        let end_line = line_offset
+            + lines
+                .iter()
+                .rposition(|line| !line.trim().is_empty())
+                .map_or(1, |x| x + 1);
+
+        if locations.is_empty() {
+            return Err(ParseError {
+                line: start_line,
+                column: 1,
+                message: "missing embedded manifest, expected `//# [package]`".to_owned(),
+            });
+        }
+
+        let locate = |offset: usize| -> (usize, usize) {
+            let index = toml[..offset].matches('\n').count();
+            let line_start = toml[..offset].rfind('\n').map_or(0, |x| x + 1);
+            let column = toml[line_start..offset].chars().count();
+            let (line, first_column) = locations[index];
+            (line, first_column + column)
+        };
+
+        let value = toml.parse::<toml::Value>().map_err(|e| {
+            let (line, column) = e
+                .span()
+                .map(|span| locate(span.start.min(toml.len())))
+                .unwrap_or(locations[0]);
+            ParseError {
+                line,
+                column,
+                message: format!("invalid manifest: {}", e.message()),
+            }
+        })?;
+
+        let package_header = || toml.find("[package]").map(locate).unwrap_or(locations[0]);
+        let name = match value.get("package").map(|x| x.get("name")) {
+            Some(Some(toml::Value::String(name))) => name.to_owned(),
+            Some(Some(_)) => {
+                let (line, column) = package_header();
+                return Err(ParseError {
+                    line,
+                    column,
+                    message: "`package.name` must be a string".to_owned(),
+                });
+            }
+            Some(None) => {
+                let (line, column) = package_header();
+                return Err(ParseError {
+                    line,
+                    column,
+                    message: "missing `name` in `[package]`".to_owned(),
+                });
+            }
+            None => {
+                let (line, column) = locations[0];
+                return Err(ParseError {
+                    line,
+                    column,
+                    message: "missing `[package]` table".to_owned(),
+                });
+            }
         };
 
-        Package { name, toml, src }
+        let lib = value.get("lib").is_some();
+        let primary_path = Path::new(if lib { "src/lib.rs" } else { "src/main.rs" });
+
+        // Lines after the manifest are split into files at `//# @file` markers,
+        // the first chunk being the primary file.
+        let mut chunks = vec![(None, Vec::new())];
+        for (index, line) in lines.iter().enumerate() {
+            if let Some(path) = file_marker(line) {
+                chunks.push((Some((index, path)), Vec::new()));
+            } else if let Some((_, chunk)) = chunks.last_mut() {
+                chunk.push((index, *line));
+            }
+        }
+        let mut chunks = chunks.into_iter();
+
+        let src_lines = chunks
+            .next()
+            .map(|(_, chunk)| chunk)
+            .unwrap_or_default()
+            .into_iter()
+            .skip_while(|(_, line)| line.is_empty() || line.starts_with("//#"))
+            .collect::<Vec<_>>();
+        let src_line = line_offset + src_lines.first().map_or(lines.len(), |x| x.0) + 1;
+        let mut src = join_lines(&src_lines);
+
+        let mut files: Vec<SourceFile> = Vec::new();
+        for (marker, chunk) in chunks {
+            let Some((index, path)) = marker else {
+                continue;
+            };
+            let error = |message: String| ParseError {
+                line: line_offset + index + 1,
+                column: lines[index].find("@file").unwrap_or(0) + 1,
+                message,
+            };
+
+            let path = validate_file_path(path).map_err(error)?;
+            let content = join_lines(&chunk);
+            if path == primary_path && src.is_empty() && files.is_empty() {
+                // The primary file may also be given explicitly.
+                src = content;
+                continue;
+            }
+            if path == primary_path || files.iter().any(|x| x.path == path) {
+                return Err(error(format!("duplicate file `{}`", path.display())));
+            }
+            files.push(SourceFile { path, content });
+        }
+
+        let local_dependencies = local_dependencies(&value)
+            .into_iter()
+            .map(|(key, name)| {
+                let (line, column) = find_key(&toml, &key).map(locate).unwrap_or(locations[0]);
+                LocalDependency { name, line, column }
+            })
+            .collect();
+
+        Ok(Package {
+            name,
+            toml,
+            src,
+            files,
+            lib,
+            local_dependencies,
+            start_line,
+            end_line,
+            src_line,
+        })
     }
-}
 
-impl Package {
-    pub(crate) fn identity_hash(&self) -> String {
-        let hash = Sha256::new()
+    /// Path of `src` in the package directory.
+    pub(crate) fn src_path(&self) -> &'static str {
+        if self.lib {
+            "src/lib.rs"
+        } else {
+            "src/main.rs"
+        }
+    }
+
+    /// The manifest to write as `Cargo.toml`,
+    /// with dependencies on sibling packages turned into `path = "../<name>"`.
+    pub(crate) fn manifest(&self) -> String {
+        if self.local_dependencies.is_empty() {
+            return self.toml.clone();
+        }
+
+        let mut document = self
+            .toml
+            .parse::<toml_edit::Document>()
+            .expect("The manifest is already parsed.");
+        rewrite_local_dependencies(document.as_table_mut());
+        document.to_string()
+    }
+
+    /// `package.default-run`, the binary to run when the package has several.
+    pub(crate) fn default_run(&self) -> Option<String> {
+        let value = self.toml.parse::<toml::Value>().ok()?;
+        let default_run = value.get("package")?.get("default-run")?.as_str()?;
+        Some(default_run.to_owned())
+    }
+
+    /// Sibling packages this package depends on, directly or not,
+    /// each listed after its own dependencies.
+    pub(crate) fn dependencies<'a>(&self, packages: &'a [Package]) -> Vec<&'a Package> {
+        fn visit<'a>(package: &Package, packages: &'a [Package], result: &mut Vec<&'a Package>) {
+            for dependency in &package.local_dependencies {
+                let Some(dependency) = packages.iter().find(|x| x.name == dependency.name) else {
+                    continue;
+                };
+                if result.iter().any(|x| x.name == dependency.name) {
+                    continue;
+                }
+                visit(dependency, packages, result);
+                result.push(dependency);
+            }
+        }
+
+        let mut result = Vec::new();
+        visit(self, packages, &mut result);
+        result
+    }
+
+    pub(crate) fn identity_hash(&self, packages: &[Package]) -> String {
+        let mut hasher = Sha256::new()
             .chain_update(&self.toml)
-            .chain_update(&self.src)
-            .finalize();
-        return format!("{:x}", hash);
+            .chain_update(&self.src);
+        for file in &self.files {
+            hasher.update(file.path.to_string_lossy().as_bytes());
+            hasher.update(&file.content);
+        }
+        // A change in a library rebuilds the packages using it.
+        for dependency in self.dependencies(packages) {
+            hasher.update(&dependency.name);
+            hasher.update(dependency.identity_hash(packages));
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Returns the path of a `//# @file <path>` marker line.
+fn file_marker(line: &str) -> Option<&str> {
+    let rest = line
+        .strip_prefix("//#")?
+        .trim_start()
+        .strip_prefix("@file")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest.trim())
+}
+
+fn validate_file_path(path: &str) -> Result<PathBuf, String> {
+    if path.is_empty() {
+        return Err("missing path after `@file`".to_owned());
+    }
+    let path = PathBuf::from(path);
+    if !path.components().all(|x| matches!(x, Component::Normal(_))) {
+        return Err(format!(
+            "`{}` must be a relative path inside the package",
+            path.display()
+        ));
+    }
+    if path == Path::new("Cargo.toml")
+        || path == Path::new("Cargo.lock")
+        || path.starts_with("target")
+    {
+        return Err(format!("`{}` is managed by cargo", path.display()));
+    }
+    if path.starts_with(".cargo") {
+        return Err(format!(
+            "`{}` would change the cargo configuration of the package",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+fn join_lines(lines: &[(usize, &str)]) -> String {
+    let lines = lines.iter().map(|x| x.1).collect::<Vec<&str>>();
+    lines.join("\n").trim_matches('\n').trim_end().to_owned()
+}
+
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// Returns the package name a dependency refers to, if it is a sibling package.
+fn local_dependency_name(
+    key: &str,
+    pit: Option<bool>,
+    path: Option<&str>,
+    package: Option<&str>,
+) -> Option<String> {
+    if pit == Some(true) {
+        return Some(package.unwrap_or(key).to_owned());
+    }
+    path.and_then(|x| x.strip_prefix('@')).map(str::to_owned)
+}
+
+/// Lists the `(key, package name)` of every dependency on a sibling package.
+fn local_dependencies(value: &toml::Value) -> Vec<(String, String)> {
+    fn collect(table: &toml::Value, result: &mut Vec<(String, String)>) {
+        for kind in DEPENDENCY_TABLES {
+            let Some(dependencies) = table.get(kind).and_then(|x| x.as_table()) else {
+                continue;
+            };
+            for (key, spec) in dependencies {
+                let name = local_dependency_name(
+                    key,
+                    spec.get("pit").and_then(|x| x.as_bool()),
+                    spec.get("path").and_then(|x| x.as_str()),
+                    spec.get("package").and_then(|x| x.as_str()),
+                );
+                if let Some(name) = name {
+                    result.push((key.to_owned(), name));
+                }
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    collect(value, &mut result);
+    if let Some(targets) = value.get("target").and_then(|x| x.as_table()) {
+        for target in targets.values() {
+            collect(target, &mut result);
+        }
     }
+    result
 }
 
-pub(crate) fn packages_from_path<P: AsRef<Path>>(file_path: P) -> Vec<Package> {
-    fs::read_to_string(file_path)
-        .expect("Failed to read string from file.")
-        .split("//# ---")
-        .map(|x| Package::from(x))
-        .collect()
+fn rewrite_local_dependencies(table: &mut dyn toml_edit::TableLike) {
+    for kind in DEPENDENCY_TABLES {
+        let Some(dependencies) = table.get_mut(kind).and_then(|x| x.as_table_like_mut()) else {
+            continue;
+        };
+        for (key, spec) in dependencies.iter_mut() {
+            let Some(spec) = spec.as_table_like_mut() else {
+                continue;
+            };
+            let name = local_dependency_name(
+                key.get(),
+                spec.get("pit").and_then(|x| x.as_bool()),
+                spec.get("path").and_then(|x| x.as_str()),
+                spec.get("package").and_then(|x| x.as_str()),
+            );
+            if let Some(name) = name {
+                spec.remove("pit");
+                spec.insert("path", toml_edit::value(format!("../{name}")));
+            }
+        }
+    }
+    if let Some(targets) = table.get_mut("target").and_then(|x| x.as_table_like_mut()) {
+        for (_, target) in targets.iter_mut() {
+            if let Some(target) = target.as_table_like_mut() {
+                rewrite_local_dependencies(target);
+            }
+        }
+    }
+}
+
+/// Returns the offset of the line defining `key` in the manifest.
+fn find_key(toml: &str, key: &str) -> Option<usize> {
+    let mut offset = 0;
+    for line in toml.split('\n') {
+        let content = line.trim_start();
+        let defines_key = content
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+            || content.trim_end().ends_with(&format!(".{key}]"));
+        if defines_key {
+            return Some(offset + line.len() - content.len());
+        }
+        offset += line.len() + 1;
+    }
+    None
+}
+
+/// Returns the error of the first dependency of `package` that can not be resolved.
+fn dependency_error(
+    package: &Package,
+    packages: &[(usize, Package)],
+    broken: &[String],
+) -> Option<ParseError> {
+    // Whether `from` depends on `to`, directly or not.
+    fn depends_on(
+        from: &str,
+        to: &str,
+        packages: &[(usize, Package)],
+        visited: &mut Vec<String>,
+    ) -> bool {
+        if visited.iter().any(|x| x == from) {
+            return false;
+        }
+        visited.push(from.to_owned());
+        let Some((_, package)) = packages.iter().find(|(_, x)| x.name == from) else {
+            return false;
+        };
+        package
+            .local_dependencies
+            .iter()
+            .any(|x| x.name == to || depends_on(&x.name, to, packages, visited))
+    }
+
+    for dependency in &package.local_dependencies {
+        let message = match packages.iter().find(|(_, x)| x.name == dependency.name) {
+            None if broken.contains(&dependency.name) => {
+                format!("package `{}` has errors", dependency.name)
+            }
+            None => format!("no package named `{}` in this snippet", dependency.name),
+            Some((_, x)) if !x.lib => format!(
+                "package `{}` is not a library, it needs a `[lib]` table",
+                dependency.name
+            ),
+            Some(_) if depends_on(&dependency.name, &package.name, packages, &mut Vec::new()) => {
+                format!("cyclic dependency on package `{}`", dependency.name)
+            }
+            Some(_) => continue,
+        };
+        return Some(ParseError {
+            line: dependency.line,
+            column: dependency.column,
+            message,
+        });
+    }
+    None
+}
+
+/// Orders packages so that libraries come before the packages depending on them,
+/// keeping the order of the file otherwise.
+pub(crate) fn build_order(packages: &[Package]) -> Vec<&Package> {
+    let mut result: Vec<&Package> = Vec::new();
+    for package in packages {
+        for package in package.dependencies(packages).into_iter().chain([package]) {
+            if !result.iter().any(|x| std::ptr::eq(*x, package)) {
+                result.push(package);
+            }
+        }
+    }
+    result
+}
+
+fn is_separator(line: &str) -> bool {
+    line.trim_end() == "//# ---"
+}
+
+/// Splits the snippet into package sections on whole `//# ---` lines.
+/// Each section is returned with the number of snippet lines preceding it,
+/// and its lines are joined with `\n` whatever the line ending of the file.
+fn split_sections(content: &str) -> Vec<(usize, String)> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+
+    let mut sections = Vec::new();
+    let mut line_offset = 0;
+    let mut lines = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if is_separator(line) {
+            sections.push((line_offset, lines.join("\n")));
+            line_offset = index + 1;
+            lines.clear();
+        } else {
+            lines.push(line.strip_suffix('\r').unwrap_or(line));
+        }
+    }
+    sections.push((line_offset, lines.join("\n")));
+
+    sections
+}
+
+/// Location of the `name` key of the package section, or of the section itself if not found.
+fn name_location(section: &str, line_offset: usize) -> (usize, usize) {
+    section
+        .lines()
+        .enumerate()
+        .find_map(|(i, line)| {
+            let key = line.trim_start().strip_prefix("//#")?.trim_start();
+            let rest = key.strip_prefix("name")?;
+            rest.trim_start()
+                .starts_with('=')
+                .then(|| (line_offset + i + 1, line.len() - key.len() + 1))
+        })
+        .unwrap_or((line_offset + 1, 1))
+}
+
+/// Parses every package section in the snippet.
+/// Broken sections do not stop parsing, they are returned as diagnostics.
+pub(crate) fn parse_snippet(file_path: &Path, content: &str) -> (Vec<Package>, Vec<Diagnostic>) {
+    let mut packages: Vec<(usize, Package)> = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for (index, (line_offset, section)) in split_sections(content).into_iter().enumerate() {
+        if section.trim().is_empty() {
+            let error = ParseError {
+                line: line_offset + 1,
+                column: 1,
+                message: "empty package section, skipped".to_owned(),
+            };
+            diagnostics.push(Diagnostic::new(Level::Warning, file_path, index + 1, error));
+            continue;
+        }
+
+        match Package::parse(&section, line_offset) {
+            Ok(package) => match packages.iter().find(|(_, x)| x.name == package.name) {
+                // Packages are told apart by name, in the cache and on the command line.
+                Some((first, _)) => {
+                    let (line, column) = name_location(&section, line_offset);
+                    let error = ParseError {
+                        line,
+                        column,
+                        message: format!(
+                            "duplicate package named `{}`, already defined in section {first}",
+                            package.name
+                        ),
+                    };
+                    diagnostics.push(Diagnostic::new(Level::Error, file_path, index + 1, error));
+                }
+                None => packages.push((index + 1, package)),
+            },
+            Err(error) => {
+                diagnostics.push(Diagnostic::new(Level::Error, file_path, index + 1, error))
+            }
+        }
+    }
+
+    // A package whose sibling dependencies can not be resolved is broken too,
+    // which may in turn break the packages depending on it.
+    let mut broken = Vec::new();
+    while let Some((position, error)) = packages
+        .iter()
+        .enumerate()
+        .find_map(|(i, (_, x))| dependency_error(x, &packages, &broken).map(|error| (i, error)))
+    {
+        let (section, package) = packages.remove(position);
+        broken.push(package.name);
+        diagnostics.push(Diagnostic::new(Level::Error, file_path, section, error));
+    }
+    diagnostics.sort_by_key(|x| x.line);
+
+    let packages = packages.into_iter().map(|(_, x)| x).collect();
+    (packages, diagnostics)
+}
+
+/// Package sections of a snippet skipped because of errors.
+#[must_use]
+pub(crate) struct SkippedSections {
+    file_path: PathBuf,
+    count: usize,
+}
+
+impl SkippedSections {
+    /// Fails if any section was skipped, once the build commands processed the valid packages.
+    pub(crate) fn check(self) -> Result<()> {
+        if self.count > 0 {
+            let plural = if self.count == 1 { "" } else { "s" };
+            bail!(
+                "{} package section{plural} of {} skipped because of errors.",
+                self.count,
+                self.file_path.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn packages_from_path<P: AsRef<Path>>(file_path: P) -> Result<Vec<Package>> {
+    let (packages, _) = packages_and_skipped_from_path(file_path)?;
+    Ok(packages)
+}
+
+/// Reads the valid packages of the snippet, reporting the others.
+pub(crate) fn packages_and_skipped_from_path<P: AsRef<Path>>(
+    file_path: P,
+) -> Result<(Vec<Package>, SkippedSections)> {
+    let file_path = file_path.as_ref();
+    let content = fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read {}", file_path.display()))?;
+
+    let (packages, diagnostics) = parse_snippet(file_path, &content);
+    for diagnostic in &diagnostics {
+        let level = match diagnostic.level {
+            Level::Error => "error:".bright_red().bold(),
+            Level::Warning => "warning:".bright_yellow().bold(),
+        };
+        eprintln!("{level} {diagnostic}");
+        emit(Event::Diagnostic {
+            level: match diagnostic.level {
+                Level::Error => "error",
+                Level::Warning => "warning",
+            },
+            file: diagnostic.file_path.clone(),
+            line: diagnostic.line,
+            column: diagnostic.column,
+            message: diagnostic.message.clone(),
+        });
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|x| x.level == Level::Error)
+        .count();
+    if packages.is_empty() && errors > 0 {
+        bail!("No valid package in {}", file_path.display());
+    }
+
+    let skipped = SkippedSections {
+        file_path: file_path.to_owned(),
+        count: errors,
+    };
+    Ok((packages, skipped))
 }
 
 #[test]
@@ -97,8 +750,291 @@ fn main() {
 }
 "#;
 
-    let package = Package::from(INPUT);
+    let package = Package::try_from(INPUT).unwrap();
     assert_eq!(package.name, NAME);
     assert_eq!(package.toml, TOML.trim());
     assert_eq!(package.src, SRC.trim());
 }
+
+#[test]
+fn parse_snippet_reports_every_broken_section() {
+    const INPUT: &str = r#"//# [package]
+//# name = "ok"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//# name = "broken
+//# version = "0.1.0"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//# version = "0.1.0"
+
+fn main() {}
+"#;
+
+    let (packages, diagnostics) = parse_snippet(Path::new("snippet.rs"), INPUT);
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].name, "ok");
+
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].section, 2);
+    assert_eq!(diagnostics[0].line, 9);
+    assert_eq!(diagnostics[1].section, 3);
+    assert_eq!((diagnostics[1].line, diagnostics[1].column), (16, 5));
+}
+
+#[test]
+fn parse_snippet_separators() {
+    const INPUT: &str = "\u{feff}//# [package]\r
+//# name = \"first\"\r
+\r
+fn main() {\r
+    println!(\"//# ---\");\r
+}\r
+//# ---   \r
+\r
+//# ---\r
+//# [package]\r
+//# name = \"second\"\r
+\r
+fn main() {}\r
+//# ---\r
+";
+
+    let (packages, diagnostics) = parse_snippet(Path::new("snippet.rs"), INPUT);
+    assert_eq!(packages.len(), 2);
+    assert_eq!(packages[0].name, "first");
+    assert_eq!(packages[0].toml, "[package]\nname = \"first\"");
+    assert_eq!(
+        packages[0].src,
+        "fn main() {\n    println!(\"//# ---\");\n}"
+    );
+    assert_eq!((packages[0].start_line, packages[0].end_line), (1, 6));
+    assert_eq!(packages[0].src_line, 4);
+    assert_eq!(packages[1].name, "second");
+    assert_eq!((packages[1].start_line, packages[1].end_line), (10, 13));
+
+    let warnings = diagnostics
+        .iter()
+        .map(|x| (x.level, x.line))
+        .collect::<Vec<_>>();
+    assert_eq!(warnings, [(Level::Warning, 8), (Level::Warning, 15)]);
+}
+
+#[test]
+fn package_with_files() {
+    const EMPTY: &str = r#"
+//# [package]
+//# name = "test"
+//# @file src/empty.rs
+"#;
+    let package = Package::try_from(EMPTY).unwrap();
+    assert_eq!(package.src, "");
+    assert_eq!(package.files.len(), 1);
+    assert_eq!(package.files[0].path, Path::new("src/empty.rs"));
+    assert_eq!(package.files[0].content, "");
+
+    const MULTI: &str = r#"
+//# [package]
+//# name = "test"
+
+mod parser;
+
+fn main() {
+    parser::parse();
+}
+
+//# @file src/parser.rs
+
+pub fn parse() {}
+//#   @file   src/parser/ast.rs
+pub struct Ast;
+"#;
+
+    let package = Package::try_from(MULTI).unwrap();
+    assert_eq!(package.toml, "[package]\nname = \"test\"");
+    assert_eq!(
+        package.src,
+        "mod parser;\n\nfn main() {\n    parser::parse();\n}"
+    );
+    let files = package
+        .files
+        .iter()
+        .map(|x| (x.path.to_str().unwrap(), x.content.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        [
+            ("src/parser.rs", "pub fn parse() {}"),
+            ("src/parser/ast.rs", "pub struct Ast;"),
+        ]
+    );
+
+    const DUPLICATE: &str = r#"
+//# [package]
+//# name = "test"
+
+fn main() {}
+//# @file src/a.rs
+//# @file src/a.rs
+"#;
+    let error = Package::try_from(DUPLICATE).unwrap_err();
+    assert_eq!((error.line, error.column), (7, 5));
+
+    const OUTSIDE: &str = r#"
+//# [package]
+//# name = "test"
+//# @file ../a.rs
+"#;
+    assert_eq!(Package::try_from(OUTSIDE).unwrap_err().line, 4);
+
+    for path in [
+        "Cargo.toml",
+        "Cargo.lock",
+        "target/a.rs",
+        ".cargo/config.toml",
+    ] {
+        let input = format!("//# [package]\n//# name = \"test\"\n//# @file {path}\n");
+        assert_eq!(Package::try_from(input.as_str()).unwrap_err().line, 3);
+    }
+}
+
+#[test]
+fn parse_snippet_local_dependencies() {
+    const INPUT: &str = r#"//# [package]
+//# name = "app"
+//#
+//# [dependencies]
+//# common = { pit = true }
+//# [dependencies.other]
+//# path = "@other"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//# name = "common"
+//#
+//# [lib]
+
+pub fn f() {}
+
+//# ---
+
+//# [package]
+//# name = "uses-missing"
+//#
+//# [dev-dependencies]
+//# missing = { pit = true }
+
+fn main() {}
+"#;
+
+    let (packages, diagnostics) = parse_snippet(Path::new("snippet.rs"), INPUT);
+    let names = packages.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["common"]);
+
+    let errors = diagnostics
+        .iter()
+        .map(|x| (x.line, x.column, x.message.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            (6, 5, "no package named `other` in this snippet"),
+            (26, 5, "no package named `missing` in this snippet"),
+        ]
+    );
+
+    let common = &packages[0];
+    assert!(common.lib);
+    assert_eq!(common.src_path(), "src/lib.rs");
+
+    let app = Package::try_from(&INPUT[..INPUT.find("//# ---").unwrap()]).unwrap();
+    assert_eq!(
+        app.manifest(),
+        r#"[package]
+name = "app"
+
+[dependencies]
+common = { path = "../common" }
+[dependencies.other]
+path = "../other"
+"#
+    );
+}
+
+#[test]
+fn build_order_puts_libraries_first() {
+    const INPUT: &str = r#"//# [package]
+//# name = "app"
+//# [dependencies]
+//# b = { pit = true }
+
+fn main() {}
+//# ---
+//# [package]
+//# name = "b"
+//# [lib]
+//# [dependencies]
+//# a = { path = "@a" }
+//# ---
+//# [package]
+//# name = "a"
+//# [lib]
+//# ---
+//# [package]
+//# name = "cycle"
+//# [lib]
+//# [dependencies]
+//# cycle = { pit = true }
+"#;
+
+    let (packages, diagnostics) = parse_snippet(Path::new("snippet.rs"), INPUT);
+    let names = build_order(&packages)
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["a", "b", "app"]);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].message,
+        "cyclic dependency on package `cycle`"
+    );
+}
+
+#[test]
+fn parse_snippet_rejects_duplicate_names() {
+    const INPUT: &str = r#"//# [package]
+//# name = "app"
+
+fn main() {}
+
+//# ---
+
+//# [package]
+//#   name = "app"
+
+fn main() {}
+"#;
+
+    let (packages, diagnostics) = parse_snippet(Path::new("snippet.rs"), INPUT);
+    assert_eq!(packages.len(), 1);
+
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].level == Level::Error);
+    assert_eq!(diagnostics[0].section, 2);
+    assert_eq!((diagnostics[0].line, diagnostics[0].column), (9, 7));
+    assert_eq!(
+        diagnostics[0].message,
+        "duplicate package named `app`, already defined in section 1"
+    );
+}
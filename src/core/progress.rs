@@ -0,0 +1,198 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::Instant,
+};
+
+use tiny_ansi::TinyAnsi;
+
+use super::{
+    output::{is_json, print_line},
+    utils::format_duration,
+};
+
+enum State {
+    Queued,
+    Compiling {
+        started: Instant,
+        crates: usize,
+        /// Packages in the lockfile, unknown before the first build.
+        total: Option<usize>,
+    },
+    Cached,
+    Done(String),
+    Failed(String),
+}
+
+impl State {
+    fn is_finished(&self) -> bool {
+        matches!(self, State::Cached | State::Done(_) | State::Failed(_))
+    }
+}
+
+struct Line {
+    name: String,
+    state: State,
+    /// Output of cargo, kept to be printed if the build fails.
+    log: Option<PathBuf>,
+}
+
+/// One line per package building in parallel, redrawn in place on a terminal.
+/// Otherwise a line is printed whenever a package starts or finishes, with the number of packages finished,
+/// and nothing in the JSON format whose events tell the same.
+pub(crate) struct Progress {
+    lines: Mutex<Vec<Line>>,
+    is_terminal: bool,
+}
+
+impl Progress {
+    pub(crate) fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Progress {
+        let progress = Progress {
+            lines: Mutex::new(
+                names
+                    .into_iter()
+                    .map(|name| Line {
+                        name: name.to_owned(),
+                        state: State::Queued,
+                        log: None,
+                    })
+                    .collect(),
+            ),
+            is_terminal: io::stdout().is_terminal() && !is_json(),
+        };
+        if progress.is_terminal {
+            let lines = progress.lines.lock().unwrap();
+            for line in lines.iter() {
+                println!("{}", render(line, width(&lines)));
+            }
+        }
+        progress
+    }
+
+    pub(super) fn start(&self, name: &str, total: Option<usize>, log: PathBuf) {
+        self.update(name, |line| {
+            line.state = State::Compiling {
+                started: Instant::now(),
+                crates: 0,
+                total,
+            };
+            line.log = Some(log);
+        });
+    }
+
+    /// Counts a crate compiled, or found fresh, by cargo.
+    pub(super) fn compiled(&self, name: &str) {
+        self.update(name, |line| {
+            if let State::Compiling { crates, .. } = &mut line.state {
+                *crates += 1;
+            }
+        });
+    }
+
+    pub(super) fn cached(&self, name: &str) {
+        self.update(name, |line| line.state = State::Cached);
+    }
+
+    pub(super) fn finish(&self, name: &str, success: bool) {
+        self.update(name, |line| {
+            let elapsed = match line.state {
+                State::Compiling { started, .. } => format_duration(started.elapsed()),
+                _ => String::new(),
+            };
+            line.state = if success {
+                State::Done(elapsed)
+            } else {
+                State::Failed(elapsed)
+            };
+        });
+    }
+
+    /// Prints a message above the lines of the packages, which are redrawn below it on a terminal.
+    pub(super) fn print(&self, text: impl Display) {
+        let lines = self.lines.lock().unwrap();
+        if !self.is_terminal {
+            print_line(text);
+            return;
+        }
+
+        // Replaces the lines with the message, then draws them again.
+        print!("\x1b[{}A\r\x1b[J", lines.len());
+        println!("{text}");
+        let width = width(&lines);
+        for line in lines.iter() {
+            println!("{}", render(line, width));
+        }
+        let _ = io::stdout().flush();
+    }
+
+    /// Prints the output of cargo for each package which failed to build.
+    pub(crate) fn print_failed_logs(&self) {
+        let lines = self.lines.lock().unwrap();
+        for line in lines.iter() {
+            let (State::Failed(_), Some(log)) = (&line.state, &line.log) else {
+                continue;
+            };
+            let output_text = format!("Log of the {} package", line.name)
+                .bright_yellow()
+                .bold();
+            print_line(output_text);
+            print_line(fs::read_to_string(log).unwrap_or_default().trim_end());
+        }
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut Line)) {
+        let mut lines = self.lines.lock().unwrap();
+        let Some(index) = lines.iter().position(|x| x.name == name) else {
+            return;
+        };
+        let was_compiling = matches!(lines[index].state, State::Compiling { .. });
+        f(&mut lines[index]);
+
+        let width = width(&lines);
+        if is_json() {
+            return;
+        }
+        if self.is_terminal {
+            // Moves up to the line of the package, redraws it, and moves back down.
+            let up = lines.len() - index;
+            print!(
+                "\x1b[{up}A\r\x1b[2K{}\x1b[{up}B\r",
+                render(&lines[index], width)
+            );
+            let _ = io::stdout().flush();
+        } else {
+            let is_compiling = matches!(lines[index].state, State::Compiling { .. });
+            if !(was_compiling && is_compiling) {
+                let mut text = render(&lines[index], width);
+                if lines[index].state.is_finished() {
+                    let finished = lines.iter().filter(|x| x.state.is_finished()).count();
+                    text.push_str(&format!(" ({finished}/{} done)", lines.len()));
+                }
+                println!("{text}");
+            }
+        }
+    }
+}
+
+fn width(lines: &[Line]) -> usize {
+    lines.iter().map(|x| x.name.len()).max().unwrap_or_default()
+}
+
+fn render(line: &Line, width: usize) -> String {
+    let state = match &line.state {
+        State::Queued => "queued".to_owned(),
+        State::Compiling {
+            crates,
+            total: Some(total),
+            ..
+        } => format!("compiling {crates}/{total} crates"),
+        State::Compiling { crates, .. } => format!("compiling {crates} crates"),
+        State::Cached => "cached".bright_green(),
+        State::Done(elapsed) => format!("done in {elapsed}").bright_green(),
+        State::Failed(elapsed) => format!("failed after {elapsed}").bright_yellow(),
+    };
+    format!("  {:<width$}  {state}", line.name)
+}
@@ -0,0 +1,199 @@
+use anyhow::{bail, Result};
+
+use super::package::{build_order, Package};
+
+/// Packages chosen with `--package` and `--exclude`.
+///
+/// Each pattern is a package name, a glob with `*` and `?`,
+/// or the 1-based position of the package in the snippet as listed by `pit list`.
+#[derive(Default)]
+pub(crate) struct Selection {
+    /// Every package if empty.
+    pub(crate) packages: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+impl Selection {
+    /// The selected packages in the order of the snippet.
+    pub(crate) fn select<'a>(&self, packages: &'a [Package]) -> Result<Vec<&'a Package>> {
+        let mut selected = vec![self.packages.is_empty(); packages.len()];
+        for pattern in &self.packages {
+            for index in matches(pattern, packages)? {
+                selected[index] = true;
+            }
+        }
+        for pattern in &self.exclude {
+            for index in matches(pattern, packages)? {
+                selected[index] = false;
+            }
+        }
+
+        Ok(packages
+            .iter()
+            .zip(selected)
+            .filter_map(|(package, selected)| selected.then_some(package))
+            .collect())
+    }
+
+    /// The selected packages, each after the libraries it depends on.
+    pub(crate) fn select_in_build_order<'a>(
+        &self,
+        packages: &'a [Package],
+    ) -> Result<Vec<&'a Package>> {
+        let selected = self.select(packages)?;
+        Ok(build_order(packages)
+            .into_iter()
+            .filter(|x| selected.iter().any(|y| y.name == x.name))
+            .collect())
+    }
+}
+
+/// Indices of the packages matching the pattern, which must match at least one.
+fn matches(pattern: &str, packages: &[Package]) -> Result<Vec<usize>> {
+    if let Ok(position) = pattern.parse::<usize>() {
+        if !(1..=packages.len()).contains(&position) {
+            bail!(
+                "No package at position {position}, the snippet has {} packages.",
+                packages.len()
+            );
+        }
+        return Ok(vec![position - 1]);
+    }
+
+    let indices = (0..packages.len())
+        .filter(|x| glob_match(pattern, &packages[*x].name))
+        .collect::<Vec<usize>>();
+    if indices.is_empty() {
+        let names = packages
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<&str>>();
+        let mut message = format!(
+            "No package matches `{pattern}`. Available packages: {}.",
+            names.join(", ")
+        );
+        let suggestions = suggestions(pattern, &names);
+        if !suggestions.is_empty() {
+            message.push_str(&format!(" Did you mean `{}`?", suggestions.join("` or `")));
+        }
+        bail!(message);
+    }
+    Ok(indices)
+}
+
+/// Whether the name matches the pattern, where `*` is any sequence of characters and `?` any character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+
+    // Position after the last `*`, and the position in the name it was matched up to.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(x) if *x == '?' || *x == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Names close to the mistyped one, the closest first.
+fn suggestions<'a>(name: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut suggestions = names
+        .iter()
+        .map(|x| (edit_distance(name, x), *x))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<(usize, &str)>>();
+    suggestions.sort();
+    suggestions.into_iter().map(|(_, x)| x).collect()
+}
+
+/// Edit distance between the two strings, where swapping two adjacent characters is one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<char>>();
+    let b = b.chars().collect::<Vec<char>>();
+
+    // `distances[i][j]` is the distance between the first `i` characters of `a` and the first `j` of `b`.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[test]
+fn select_packages() {
+    let packages = ["app", "bench-a", "bench-b", "common"]
+        .iter()
+        .map(|name| Package::parse(&format!("//# [package]\n//# name = \"{name}\"\n"), 0).unwrap())
+        .collect::<Vec<Package>>();
+    let names = |selection: Selection| {
+        selection
+            .select(&packages)
+            .map(|x| x.iter().map(|x| x.name.clone()).collect::<Vec<String>>())
+    };
+    let selection = |packages: &[&str], exclude: &[&str]| Selection {
+        packages: packages.iter().map(|x| x.to_string()).collect(),
+        exclude: exclude.iter().map(|x| x.to_string()).collect(),
+    };
+
+    assert_eq!(names(selection(&[], &[])).unwrap().len(), 4);
+    assert_eq!(
+        names(selection(&["common", "app"], &[])).unwrap(),
+        ["app", "common"]
+    );
+    assert_eq!(
+        names(selection(&["bench-*"], &["bench-b"])).unwrap(),
+        ["bench-a"]
+    );
+    assert_eq!(names(selection(&[], &["b*", "1"])).unwrap(), ["common"]);
+    assert_eq!(names(selection(&["2"], &[])).unwrap(), ["bench-a"]);
+    assert!(names(selection(&["5"], &[])).is_err());
+
+    let error = names(selection(&["comon"], &[])).unwrap_err().to_string();
+    assert!(error.contains("Did you mean `common`?"), "{error}");
+    assert!(error.contains("app, bench-a, bench-b, common"), "{error}");
+}
+
+#[test]
+fn glob_and_edit_distance() {
+    assert!(glob_match("bench-*", "bench-a"));
+    assert!(glob_match("*-a", "bench-a"));
+    assert!(glob_match("b?nch*a", "bench-a"));
+    assert!(!glob_match("bench-?", "bench-ab"));
+    assert!(!glob_match("*x*", "bench"));
+
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("same", "same"), 0);
+    assert_eq!(edit_distance("tow", "two"), 1);
+}
@@ -0,0 +1,231 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde_derive::Serialize;
+use tiny_ansi::TinyAnsi;
+
+use super::{
+    output::{emit, print_line, Event},
+    package::Package,
+    utils::{format_duration, format_size},
+    BuildOptions, RunStatus,
+};
+
+enum Status {
+    /// Not reached, because of an earlier failure or as a library which is not run.
+    Skipped,
+    Built,
+    Cached,
+    Failed,
+    Ran {
+        exit_code: i32,
+        description: String,
+    },
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Skipped => "skipped",
+            Status::Built => "built",
+            Status::Cached => "cached",
+            Status::Failed => "failed",
+            Status::Ran { .. } => "ran",
+        }
+    }
+}
+
+struct Row {
+    name: String,
+    status: Status,
+    build_time: Option<Duration>,
+    run_time: Option<Duration>,
+    /// Total size of the executables, none for a package without any.
+    artifact_size: Option<u64>,
+}
+
+/// A row of the summary file and of the summary event.
+#[derive(Serialize)]
+pub(crate) struct Record {
+    package: String,
+    status: &'static str,
+    exit_code: Option<i32>,
+    /// In seconds.
+    build_time: Option<f64>,
+    run_time: Option<f64>,
+    /// In bytes.
+    artifact_size: Option<u64>,
+}
+
+/// What happened to each package of a command, printed as a table at its end.
+pub(crate) struct Summary {
+    rows: Mutex<Vec<Row>>,
+}
+
+impl Summary {
+    fn new(packages: &[&Package]) -> Summary {
+        Summary {
+            rows: Mutex::new(
+                packages
+                    .iter()
+                    .map(|package| Row {
+                        name: package.name.clone(),
+                        status: Status::Skipped,
+                        build_time: None,
+                        run_time: None,
+                        artifact_size: None,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub(super) fn built(&self, name: &str, build_time: Duration, artifact_size: Option<u64>) {
+        self.update(name, |row| {
+            row.status = Status::Built;
+            row.build_time = Some(build_time);
+            row.artifact_size = artifact_size;
+        });
+    }
+
+    pub(super) fn cached(&self, name: &str, artifact_size: Option<u64>) {
+        self.update(name, |row| {
+            row.status = Status::Cached;
+            row.artifact_size = artifact_size;
+        });
+    }
+
+    /// Marks the package as failed, unless it ran, in which case its exit is kept.
+    pub(super) fn failed(&self, name: &str) {
+        self.update(name, |row| {
+            if !matches!(row.status, Status::Ran { .. }) {
+                row.status = Status::Failed;
+            }
+        });
+    }
+
+    pub(super) fn ran(&self, name: &str, status: &RunStatus, run_time: Duration) {
+        self.update(name, |row| {
+            row.status = Status::Ran {
+                exit_code: status.exit_code(),
+                description: status.description(),
+            };
+            row.run_time = Some(run_time);
+        });
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut Row)) {
+        let mut rows = self.rows.lock().unwrap();
+        if let Some(row) = rows.iter_mut().find(|x| x.name == name) {
+            f(row);
+        }
+    }
+
+    fn print(&self) {
+        let rows = self.rows.lock().unwrap();
+        let optional = |x: Option<String>| x.unwrap_or_else(|| "-".to_owned());
+        let cells = rows
+            .iter()
+            .map(|row| {
+                [
+                    row.name.clone(),
+                    match &row.status {
+                        Status::Ran { description, .. } => description.clone(),
+                        status => status.name().to_owned(),
+                    },
+                    optional(row.build_time.map(format_duration)),
+                    optional(row.run_time.map(format_duration)),
+                    optional(row.artifact_size.map(format_size)),
+                ]
+            })
+            .collect::<Vec<[String; 5]>>();
+
+        let header = ["Package", "Status", "Build", "Run", "Size"];
+        let widths = (0..header.len())
+            .map(|i| {
+                cells
+                    .iter()
+                    .map(|x| x[i].len())
+                    .chain([header[i].len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<usize>>();
+        let render = |cells: &[&str]| {
+            let line = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<String>>()
+                .join("  ");
+            format!("  {}", line.trim_end())
+        };
+
+        print_line(render(&header).bold());
+        for row in &cells {
+            print_line(render(&row.each_ref().map(String::as_str)));
+        }
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let rows = self.rows.lock().unwrap();
+        rows.iter()
+            .map(|row| Record {
+                package: row.name.clone(),
+                status: row.status.name(),
+                exit_code: match row.status {
+                    Status::Ran { exit_code, .. } => Some(exit_code),
+                    _ => None,
+                },
+                build_time: row.build_time.map(|x| x.as_secs_f64()),
+                run_time: row.run_time.map(|x| x.as_secs_f64()),
+                artifact_size: row.artifact_size,
+            })
+            .collect()
+    }
+
+    /// Writes the summary as a JSON array with one object per package.
+    fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(&self.records())?;
+        fs::write(path, content + "\n")
+            .with_context(|| format!("Failed to write the summary to {}", path.display()))
+    }
+}
+
+/// Runs the command with a summary of the packages, which is printed at the end if there are several,
+/// and written to `--summary-file` if given, even if the command failed.
+pub(crate) fn with_summary<T>(
+    packages: &[&Package],
+    options: &BuildOptions,
+    f: impl FnOnce(&BuildOptions) -> Result<T>,
+) -> Result<T> {
+    let summary = Arc::new(Summary::new(packages));
+    let result = f(&BuildOptions {
+        summary: Some(summary.clone()),
+        ..options.clone()
+    });
+
+    if packages.len() > 1 {
+        summary.print();
+    }
+    emit(Event::Summary {
+        packages: summary.records(),
+    });
+    let written = match &options.summary_file {
+        Some(path) => summary.write(path),
+        None => Ok(()),
+    };
+    match (result, written) {
+        // The failure of the command comes first, the summary file is only reported.
+        (Err(error), Err(write_error)) => {
+            eprintln!("{}", format!("{write_error:#}").bright_yellow().bold());
+            Err(error)
+        }
+        (result, written) => written.and(result),
+    }
+}
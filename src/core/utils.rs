@@ -1,8 +1,15 @@
-use std::{fs, path::Path};
+use std::{
+    fs, io,
+    path::Path,
+    process,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use rand::{seq::SliceRandom, thread_rng};
 
+use super::package::{Package, SourceFile};
+
 pub(super) fn random_name() -> String {
     let random_str: String = "abcdefghijklmnopqrstuvwxyz0123456789"
         .as_bytes()
@@ -20,15 +27,38 @@ pub(super) fn create_toml<P: AsRef<Path>>(package_dir: P, toml: &str) -> Result<
     Ok(())
 }
 
-pub(super) fn create_src<P: AsRef<Path>>(package_dir: P, src: &str) -> Result<()> {
-    let src_dir = package_dir.as_ref().join("src");
-    fs::create_dir(&src_dir)?;
-    let src_file = src_dir.join("main.rs");
+pub(super) fn create_src<P: AsRef<Path>>(package_dir: P, src_path: &str, src: &str) -> Result<()> {
+    let src_file = package_dir.as_ref().join(src_path);
+    fs::create_dir_all(src_file.parent().unwrap())?;
     fs::write(src_file, src.as_bytes())?;
 
     Ok(())
 }
 
+pub(super) fn create_files<P: AsRef<Path>>(package_dir: P, files: &[SourceFile]) -> Result<()> {
+    for file in files {
+        let file_path = package_dir.as_ref().join(&file.path);
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(file_path, file.content.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Writes the manifest and every source file of the package.
+pub(super) fn create_package<P: AsRef<Path>>(package_dir: P, package: &Package) -> Result<()> {
+    let package_dir = package_dir.as_ref();
+    fs::create_dir_all(package_dir)?;
+
+    create_toml(package_dir, &package.manifest())?;
+    create_src(package_dir, package.src_path(), &package.src)?;
+    create_files(package_dir, &package.files)?;
+
+    Ok(())
+}
+
 pub(super) fn create_gitignore<P: AsRef<Path>>(package_dir: P) -> Result<()> {
     let gitignore = package_dir.as_ref().join(".gitignore");
     let contents = r#"
@@ -39,3 +69,173 @@ pub(super) fn create_gitignore<P: AsRef<Path>>(package_dir: P) -> Result<()> {
 
     Ok(())
 }
+
+/// Renames the directory, or copies and removes it if it is on another file system.
+/// Nothing is done if another process moved it first.
+pub(super) fn move_dir<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            // Copied under another name first, so that a half copied directory is never used.
+            let partial = to.with_extension(format!("partial-{}", process::id()));
+            copy_dir(from, &partial)?;
+            fs::rename(&partial, to)?;
+            fs::remove_dir_all(from)?;
+            Ok(())
+        }
+        Err(_) if !from.exists() || to.exists() => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in from.read_dir()? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Total size of the files in the directory.
+pub(super) fn dir_size<P: AsRef<Path>>(dir: P) -> u64 {
+    let Ok(entries) = dir.as_ref().read_dir() else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+const SIZE_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+pub(super) fn format_size(size: u64) -> String {
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", SIZE_UNITS[unit])
+    }
+}
+
+pub(super) fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    let (value, unit) = match secs {
+        0..60 => return "just now".to_owned(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    let plural = if value == 1 { "" } else { "s" };
+    format!("{value} {unit}{plural} ago")
+}
+
+/// Formats how long a step took, such as `3.2s` or `2m 05s`.
+pub(super) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Parses a duration such as `90s`, `45m`, `12h`, `30d` or `2w`.
+pub(crate) fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration `{input}`"))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => bail!("Invalid duration `{input}`, expected a unit of s, m, h, d or w"),
+    };
+
+    Ok(Duration::from_secs(value * secs))
+}
+
+/// Parses a size such as `512M`, `10G` or `1.5GiB`, in powers of 1024.
+pub(crate) fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("Invalid size `{input}`"))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit.trim_end_matches("IB").trim_end_matches('B');
+    let Some(exponent) = ["", "K", "M", "G", "T"].iter().position(|x| *x == unit) else {
+        bail!("Invalid size `{input}`, expected a unit of B, K, M, G or T");
+    };
+
+    Ok((value * 1024f64.powi(exponent as i32)) as u64)
+}
+
+#[test]
+fn parse_durations_and_sizes() {
+    assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+    assert_eq!(
+        parse_duration("30d").unwrap(),
+        Duration::from_secs(30 * 86400)
+    );
+    assert!(parse_duration("30").is_err());
+    assert!(parse_duration("d").is_err());
+
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("10G").unwrap(), 10 * 1024 * 1024 * 1024);
+    assert_eq!(parse_size("1.5KiB").unwrap(), 1536);
+    assert_eq!(parse_size("2mb").unwrap(), 2 * 1024 * 1024);
+    assert!(parse_size("10X").is_err());
+
+    assert_eq!(format_size(512), "512 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+}
+
+#[test]
+fn move_dirs() {
+    let temp_dir = std::env::temp_dir().join(format!("rspit-test-move-dir-{}", process::id()));
+    let (from, to) = (temp_dir.join("from"), temp_dir.join("to"));
+    fs::create_dir_all(from.join("target")).unwrap();
+    fs::write(from.join("target/file"), "content").unwrap();
+
+    move_dir(&from, &to).unwrap();
+    assert!(!from.exists());
+    assert_eq!(
+        fs::read_to_string(to.join("target/file")).unwrap(),
+        "content"
+    );
+    // Already moved by another pit.
+    move_dir(&from, &to).unwrap();
+
+    fs::remove_dir_all(temp_dir).unwrap();
+}
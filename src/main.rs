@@ -1,3 +1,5 @@
+use std::process;
+
 use anyhow::Result;
 
 mod cli;
@@ -5,7 +7,29 @@ mod commands;
 mod core;
 
 fn main() -> Result<()> {
-    cli::main()?;
+    let result = cli::main();
+    if core::is_interrupted() {
+        process::exit(130);
+    }
+    if let Err(error) = &result {
+        core::emit(core::Event::Error {
+            message: format!("{error:#}"),
+        });
+    }
+    let exit_code = match result {
+        Ok(exit_code) => exit_code,
+        // The package which failed the run gives its exit code to pit.
+        Err(error) => match error.downcast::<core::RunFailure>() {
+            Ok(failure) => {
+                eprintln!("Error: {failure}");
+                failure.exit_code
+            }
+            Err(error) => return Err(error),
+        },
+    };
+    if exit_code != 0 {
+        process::exit(exit_code);
+    }
 
     Ok(())
 }